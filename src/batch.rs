@@ -0,0 +1,238 @@
+use super::config::Batch;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// How long to wait for more codeblocks before a batch is formatted.
+const DELAY: Duration = Duration::from_millis(50);
+
+struct Job {
+    batch: Batch,
+    content: String,
+    reply: oneshot::Sender<Result<String, String>>,
+}
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Collects the codeblocks of one run into batches. It stops when the last
+/// clone is dropped.
+#[derive(Clone)]
+pub struct Worker {
+    sender: mpsc::UnboundedSender<Job>,
+}
+
+impl Worker {
+    pub fn start() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(collect(receiver));
+        Worker { sender }
+    }
+
+    /// Formats the content together with other codeblocks that use the same
+    /// batch command, from all documents. The command is called with temporary
+    /// files and has to format them in place.
+    pub async fn format(&self, batch: &Batch, content: &str) -> Result<String, String> {
+        let (reply, receiver) = oneshot::channel();
+        self.sender
+            .send(Job {
+                batch: batch.clone(),
+                content: content.to_string(),
+                reply,
+            })
+            .map_err(|e| e.to_string())?;
+        receiver.await.map_err(|e| e.to_string())?
+    }
+}
+
+/// Directory for the files of a batch, it is removed when dropped.
+struct Dir(PathBuf);
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Collects jobs until a batch is full or no new jobs came in for a while.
+async fn collect(mut receiver: mpsc::UnboundedReceiver<Job>) {
+    let mut pending: Vec<(Batch, Vec<Job>)> = Vec::new();
+    loop {
+        let job = if pending.is_empty() {
+            receiver.recv().await
+        } else {
+            match tokio::time::timeout(DELAY, receiver.recv()).await {
+                Ok(job) => job,
+                Err(_) => {
+                    for (batch, jobs) in pending.drain(..) {
+                        tokio::task::spawn_blocking(move || run(&batch, jobs));
+                    }
+                    continue;
+                }
+            }
+        };
+        let job = match job {
+            Some(j) => j,
+            None => {
+                for (batch, jobs) in pending.drain(..) {
+                    tokio::task::spawn_blocking(move || run(&batch, jobs));
+                }
+                break;
+            }
+        };
+
+        let index = match pending.iter().position(|(b, _)| *b == job.batch) {
+            Some(i) => i,
+            None => {
+                pending.push((job.batch.clone(), Vec::new()));
+                pending.len() - 1
+            }
+        };
+        pending[index].1.push(job);
+        if pending[index].1.len() >= pending[index].0.size {
+            let (batch, jobs) = pending.remove(index);
+            tokio::task::spawn_blocking(move || run(&batch, jobs));
+        }
+    }
+}
+
+fn run(batch: &Batch, jobs: Vec<Job>) {
+    let dir = create_dir(&std::env::temp_dir());
+    let results = dir
+        .as_ref()
+        .map_err(|e| e.to_owned())
+        .and_then(|d| write_files(&d.0, batch, &jobs))
+        .map(|files| {
+            // If the batch fails, each file is formatted on its own to find the one that fails
+            match call(batch, &files) {
+                Ok(()) => files.iter().map(|f| read_file(f)).collect::<Vec<_>>(),
+                Err(_) => files
+                    .iter()
+                    .map(|f| call(batch, std::slice::from_ref(f)).and_then(|_| read_file(f)))
+                    .collect(),
+            }
+        });
+    drop(dir);
+
+    match results {
+        Ok(results) => {
+            for (job, result) in jobs.into_iter().zip(results) {
+                let _ = job.reply.send(result);
+            }
+        }
+        Err(e) => {
+            for job in jobs {
+                let _ = job.reply.send(Err(e.to_owned()));
+            }
+        }
+    }
+}
+
+/// Creates a new directory for the files of a batch in `parent`. Only the user
+/// can access it.
+fn create_dir(parent: &Path) -> Result<Dir, String> {
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+    // The directory must not exist yet, one that someone else created is never used
+    loop {
+        let dir = parent.join(format!(
+            ".cbfmt-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        match builder.create(&dir) {
+            Ok(()) => return Ok(Dir(dir)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+fn write_files(dir: &Path, batch: &Batch, jobs: &[Job]) -> Result<Vec<PathBuf>, String> {
+    jobs.iter()
+        .enumerate()
+        .map(|(i, job)| {
+            let file = dir.join(format!("{i}.{}", batch.extension));
+            std::fs::write(&file, &job.content).map_err(|e| e.to_string())?;
+            Ok(file)
+        })
+        .collect()
+}
+
+fn read_file(file: &Path) -> Result<String, String> {
+    std::fs::read_to_string(file).map_err(|e| e.to_string())
+}
+
+fn call(batch: &Batch, files: &[PathBuf]) -> Result<(), String> {
+    let mut args = batch.command.split_whitespace();
+    let cmd = args.next().ok_or("No command provided.")?;
+    let output = Command::new(cmd)
+        .args(args)
+        .args(files)
+        .output()
+        .map_err(|e| e.to_string())?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let batch = Batch {
+            command: "sed -i s/x/y/".to_string(),
+            extension: "txt".to_string(),
+            size: 2,
+        };
+        let mut receivers = Vec::new();
+        let jobs = ["x\n", "a x\n"]
+            .iter()
+            .map(|content| {
+                let (reply, receiver) = oneshot::channel();
+                receivers.push(receiver);
+                Job {
+                    batch: batch.clone(),
+                    content: content.to_string(),
+                    reply,
+                }
+            })
+            .collect();
+        run(&batch, jobs);
+
+        let results = receivers
+            .into_iter()
+            .map(|mut r| r.try_recv().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![Ok("y\n".to_string()), Ok("a y\n".to_string())],
+            results
+        );
+    }
+
+    #[test]
+    fn test_create_dir() {
+        let parent = std::env::temp_dir();
+        let first = create_dir(&parent).unwrap();
+        let second = create_dir(&parent).unwrap();
+        assert_ne!(first.0, second.0);
+        assert_eq!(parent, first.0.parent().unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&first.0).unwrap().permissions().mode();
+            assert_eq!(0o700, mode & 0o777);
+        }
+        let path = first.0.clone();
+        drop(first);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,315 @@
+use quick_xml::events::Event;
+use quick_xml::{Reader, Writer};
+use toml_edit::{DocumentMut, Item, Table};
+use yaml_rust2::parser::Parser;
+use yaml_rust2::{Event as YamlEvent, YamlEmitter, YamlLoader};
+
+/// Formatter commands with this prefix use a formatter built into cbfmt.
+pub const PREFIX: &str = "builtin:";
+
+/// A formatter that runs inside cbfmt, without an external command.
+trait Formatter {
+    fn format(&self, content: &str) -> Result<String, String>;
+}
+
+struct Json;
+struct Toml;
+struct Yaml;
+struct Xml;
+
+fn get(name: &str) -> Option<&'static dyn Formatter> {
+    match name {
+        "json" => Some(&Json),
+        "toml" => Some(&Toml),
+        "yaml" => Some(&Yaml),
+        "xml" => Some(&Xml),
+        _ => None,
+    }
+}
+
+/// Formats the content with the built-in formatter.
+pub fn format(name: &str, content: &str) -> Result<String, String> {
+    let formatter = get(name).ok_or_else(|| format!("There is no built-in formatter {name}."))?;
+    if content.trim().is_empty() {
+        return Ok(content.to_string());
+    }
+    formatter.format(content)
+}
+
+impl Formatter for Json {
+    fn format(&self, content: &str) -> Result<String, String> {
+        let value: serde_json::Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+        let mut result = serde_json::to_string_pretty(&value).map_err(|e| e.to_string())?;
+        result.push('\n');
+        Ok(result)
+    }
+}
+
+impl Formatter for Toml {
+    fn format(&self, content: &str) -> Result<String, String> {
+        let mut document = content.parse::<DocumentMut>().map_err(|e| e.to_string())?;
+        format_table(document.as_table_mut());
+        let result = document.to_string();
+        Ok(format!("{}\n", result.trim()))
+    }
+}
+
+/// Normalizes the whitespace around keys, values, and table headers. Comments
+/// are kept, and tables are separated by one empty line.
+fn format_table(table: &mut Table) {
+    let prefix = get_comments(table.decor().prefix().and_then(|p| p.as_str()));
+    let suffix = get_trailing_comment(table.decor().suffix().and_then(|s| s.as_str()));
+    table
+        .decor_mut()
+        .set_prefix(format!("\n{}", prefix.trim_start_matches('\n')));
+    table.decor_mut().set_suffix(suffix);
+
+    for (mut key, item) in table.iter_mut() {
+        match item {
+            Item::Value(value) => {
+                let prefix = get_comments(key.leaf_decor().prefix().and_then(|p| p.as_str()));
+                let suffix = get_trailing_comment(value.decor().suffix().and_then(|s| s.as_str()));
+                key.leaf_decor_mut().set_prefix(prefix);
+                key.leaf_decor_mut().set_suffix(" ");
+                value.decor_mut().set_prefix(" ");
+                value.decor_mut().set_suffix(suffix);
+            }
+            Item::Table(t) if !t.is_dotted() => format_table(t),
+            Item::ArrayOfTables(tables) => tables.iter_mut().for_each(format_table),
+            _ => {}
+        }
+    }
+}
+
+/// Returns the comment lines of a prefix without indentation. Consecutive
+/// empty lines are merged into one.
+fn get_comments(prefix: Option<&str>) -> String {
+    let mut lines = prefix.unwrap_or_default().split('\n').collect::<Vec<_>>();
+    // The last line is the indentation before the key
+    lines.pop();
+
+    let mut result = String::new();
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() && (result == "\n" || result.ends_with("\n\n")) {
+            continue;
+        }
+        result.push_str(line);
+        result.push('\n');
+    }
+    result
+}
+
+fn get_trailing_comment(suffix: Option<&str>) -> String {
+    match suffix.map(|s| s.trim()) {
+        Some(comment) if comment.starts_with('#') => format!(" {comment}"),
+        _ => String::new(),
+    }
+}
+
+impl Formatter for Yaml {
+    fn format(&self, content: &str) -> Result<String, String> {
+        if content
+            .lines()
+            .any(|l| l.trim_start().starts_with('#') || l.contains(" #"))
+        {
+            return Err(String::from(
+                "builtin:yaml can not keep comments, use an external formatter.",
+            ));
+        }
+
+        let scalars = get_yaml_scalars(content)?;
+        let documents = YamlLoader::load_from_str(content).map_err(|e| e.to_string())?;
+        let mut result = String::new();
+        for (i, document) in documents.iter().enumerate() {
+            let mut output = String::new();
+            let mut emitter = YamlEmitter::new(&mut output);
+            emitter.multiline_strings(true);
+            emitter.dump(document).map_err(|e| e.to_string())?;
+            if i > 0 {
+                result.push_str("---\n");
+            }
+            // The emitter starts every document with a marker, it is only needed between them
+            result.push_str(output.strip_prefix("---\n").unwrap_or(&output));
+            result.push('\n');
+        }
+
+        // Numbers and nulls are written in their canonical form, `010` would become `10`
+        if get_yaml_scalars(&result)? != scalars {
+            return Err(String::from(
+                "builtin:yaml would change how values are written, use an external formatter.",
+            ));
+        }
+        Ok(result)
+    }
+}
+
+/// Returns the values of all scalars. Tags, anchors, and aliases are an error,
+/// they are lost when the document is loaded.
+fn get_yaml_scalars(content: &str) -> Result<Vec<String>, String> {
+    let error = || {
+        Err(String::from(
+            "builtin:yaml can not keep tags, anchors, and aliases, use an external formatter.",
+        ))
+    };
+    let mut parser = Parser::new_from_str(content);
+    let mut scalars = Vec::new();
+    loop {
+        match parser.next_token().map_err(|e| e.to_string())?.0 {
+            YamlEvent::StreamEnd => return Ok(scalars),
+            YamlEvent::Alias(_) => return error(),
+            YamlEvent::Scalar(_, _, anchor, tag)
+            | YamlEvent::SequenceStart(anchor, tag)
+            | YamlEvent::MappingStart(anchor, tag)
+                if anchor != 0 || tag.is_some() =>
+            {
+                return error()
+            }
+            YamlEvent::Scalar(value, ..) => scalars.push(value),
+            _ => {}
+        }
+    }
+}
+
+impl Formatter for Xml {
+    fn format(&self, content: &str) -> Result<String, String> {
+        let mut reader = Reader::from_str(content);
+        let mut events = Vec::new();
+        loop {
+            match reader.read_event().map_err(|e| e.to_string())? {
+                Event::Eof => break,
+                event => events.push(event),
+            }
+        }
+        let mixed = get_mixed_content(&events);
+
+        // Elements with text keep their content as it is, whitespace in them can be meaningful
+        let mut writer = Writer::new(Vec::new());
+        let mut depth = 0;
+        let mut verbatim = 0;
+        let mut is_empty = false;
+        for (i, event) in events.into_iter().enumerate() {
+            if verbatim > 0 {
+                match event {
+                    Event::Start(_) => verbatim += 1,
+                    Event::End(_) => verbatim -= 1,
+                    _ => {}
+                }
+                if verbatim == 0 {
+                    depth -= 1;
+                }
+                writer.write_event(event).map_err(|e| e.to_string())?;
+                continue;
+            }
+
+            match event {
+                Event::Text(ref text) if text.iter().all(u8::is_ascii_whitespace) => continue,
+                Event::End(_) => {
+                    depth -= 1;
+                    if !is_empty {
+                        write_indent(&mut writer, depth);
+                    }
+                }
+                _ => write_indent(&mut writer, depth),
+            }
+            is_empty = matches!(event, Event::Start(_));
+            if is_empty {
+                depth += 1;
+                if mixed.contains(&i) {
+                    verbatim = 1;
+                }
+            }
+            writer.write_event(event).map_err(|e| e.to_string())?;
+        }
+
+        let mut result = String::from_utf8(writer.into_inner()).map_err(|e| e.to_string())?;
+        result.push('\n');
+        Ok(result)
+    }
+}
+
+/// Returns the indexes of the start events of elements that contain text.
+fn get_mixed_content(events: &[Event]) -> Vec<usize> {
+    let mut result = Vec::new();
+    let mut stack: Vec<(usize, bool)> = Vec::new();
+    for (i, event) in events.iter().enumerate() {
+        match event {
+            Event::Start(_) => stack.push((i, false)),
+            Event::End(_) => {
+                if let Some((start, true)) = stack.pop() {
+                    result.push(start);
+                }
+            }
+            Event::Text(text) if text.iter().all(u8::is_ascii_whitespace) => {}
+            Event::Text(_) | Event::CData(_) => {
+                if let Some((_, has_text)) = stack.last_mut() {
+                    *has_text = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+fn write_indent(writer: &mut Writer<Vec<u8>>, depth: usize) {
+    let output = writer.get_mut();
+    if !output.is_empty() {
+        output.push(b'\n');
+        output.extend(std::iter::repeat_n(b' ', depth * 2));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_json() {
+        assert_eq!(
+            Ok("{\n  \"b\": 1.0,\n  \"a\": [\n    12345678901234567890\n  ]\n}\n".to_string()),
+            format("json", "{\"b\":1.0,\"a\":[12345678901234567890]}")
+        );
+        assert!(format("json", "{\"a\":}").is_err());
+    }
+
+    #[test]
+    fn test_format_toml() {
+        let content = "# header\nname=\"cbfmt\"   # name\n\n\n  # list\nlist = [1,2]\n[package]\nversion =  \"1\"\n";
+        assert_eq!(
+            Ok("# header\nname = \"cbfmt\" # name\n\n# list\nlist = [1,2]\n\n[package]\nversion = \"1\"\n".to_string()),
+            format("toml", content)
+        );
+    }
+
+    #[test]
+    fn test_format_yaml() {
+        assert_eq!(
+            Ok("a:\n  - 1\n  - b: c\n---\nd: e\n".to_string()),
+            format("yaml", "a: [1, {b: c}]\n---\nd:   e\n")
+        );
+        assert_eq!(Ok("a: x\n".to_string()), format("yaml", "a: \"x\"\n"));
+        assert!(format("yaml", "a: 1 # one\n").is_err());
+        assert!(format("yaml", "a: !Ref Foo\n").is_err());
+        assert!(format("yaml", "a: &a 1\nb: *a\n").is_err());
+        assert!(format("yaml", "a: 010\n").is_err());
+    }
+
+    #[test]
+    fn test_format_xml() {
+        assert_eq!(
+            Ok(
+                "<?xml version=\"1.0\"?>\n<a>\n  <!-- b -->\n  <b x=\"1\">\n text </b>\n  <c></c>\n</a>\n"
+                    .to_string()
+            ),
+            format(
+                "xml",
+                "<?xml version=\"1.0\"?>\n<a><!-- b --><b x=\"1\">\n text </b>\n<c></c></a>"
+            )
+        );
+        assert_eq!(
+            Ok("<div>\n  <p>Hello <b>world</b> !</p>\n  <br/>\n</div>\n".to_string()),
+            format("xml", "<div><p>Hello <b>world</b> !</p> <br/></div>")
+        );
+    }
+}
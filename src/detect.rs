@@ -0,0 +1,199 @@
+/// Guesses the language of an untagged codeblock from shebang lines, editor
+/// modelines, and a few simple heuristics.
+pub fn detect_language(content: &str) -> Option<String> {
+    if let Some(language) = from_shebang(content) {
+        return Some(language);
+    }
+    if let Some(language) = from_modeline(content) {
+        return Some(language);
+    }
+    from_heuristics(content).map(|l| l.to_string())
+}
+
+fn from_shebang(content: &str) -> Option<String> {
+    let line = content.lines().next()?.strip_prefix("#!")?;
+    let mut words = line.split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|w| !w.starts_with('-'))?;
+    }
+    let interpreter = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+
+    let language = match interpreter {
+        "node" | "nodejs" | "deno" => "javascript",
+        "python" => "python",
+        "ruby" => "ruby",
+        "perl" => "perl",
+        "php" => "php",
+        "lua" => "lua",
+        "bash" | "sh" | "zsh" | "fish" | "dash" | "ksh" => interpreter,
+        _ => return None,
+    };
+    Some(language.to_string())
+}
+
+fn from_modeline(content: &str) -> Option<String> {
+    let lines = content.lines().collect::<Vec<_>>();
+    let first = lines.iter().take(5);
+    let last = lines.iter().rev().take(5);
+
+    for line in first.chain(last) {
+        // vim: set ft=python:
+        for key in ["filetype=", "ft=", "syntax="] {
+            if let Some(p) = line.find(key) {
+                if line.contains("vim:") || line.contains("vi:") || line.contains("ex:") {
+                    let value = &line[p + key.len()..];
+                    let value = value
+                        .split(|c: char| c == ':' || c.is_whitespace())
+                        .next()
+                        .unwrap_or("");
+                    if !value.is_empty() {
+                        return Some(value.to_string());
+                    }
+                }
+            }
+        }
+
+        // -*- mode: python -*-
+        if let Some(p) = line.find("-*-") {
+            let rest = &line[p + 3..];
+            let inner = match rest.find("-*-") {
+                Some(end) => &rest[..end],
+                None => continue,
+            };
+            let value = match inner.find("mode:") {
+                Some(m) => inner[m + 5..].split(';').next().unwrap_or(""),
+                None if !inner.contains(':') => inner,
+                None => "",
+            };
+            let value = value.trim().trim_end_matches("-mode");
+            if !value.is_empty() {
+                return Some(value.to_lowercase());
+            }
+        }
+    }
+
+    None
+}
+
+fn from_heuristics(content: &str) -> Option<&'static str> {
+    let text = content.trim_start();
+    let has_line = |prefix: &str| content.lines().any(|l| l.trim_start().starts_with(prefix));
+
+    if text.starts_with("<?php") {
+        return Some("php");
+    }
+    if text.starts_with("<?xml") {
+        return Some("xml");
+    }
+    if text.starts_with("<!DOCTYPE html") || text.starts_with("<html") {
+        return Some("html");
+    }
+    if (text.starts_with('{') || text.starts_with('[')) && is_json(text) {
+        return Some("json");
+    }
+    if has_line("package ") && has_line("func ") {
+        return Some("go");
+    }
+    if has_line("fn ") || has_line("use std::") || has_line("let mut ") || has_line("impl ") {
+        return Some("rust");
+    }
+    if has_line("#include ") {
+        return Some("c");
+    }
+    if (has_line("def ") || has_line("class "))
+        && content.lines().any(|l| l.trim_end().ends_with(':'))
+    {
+        return Some("python");
+    }
+    if has_line("import ") && has_line("from ") && !content.contains(';') {
+        return Some("python");
+    }
+    if has_line("const ") || has_line("function ") || has_line("console.log(") {
+        return Some("javascript");
+    }
+    None
+}
+
+/// Checks that brackets and quotes are balanced and that there is no code
+/// outside of strings, good enough to tell JSON from other languages.
+fn is_json(text: &str) -> bool {
+    let mut depth = 0i32;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for ch in text.chars() {
+        if in_string {
+            match ch {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match ch {
+            '"' => in_string = true,
+            '{' | '[' => depth += 1,
+            '}' | ']' => depth -= 1,
+            ',' | ':' | '.' | '-' | '+' => {}
+            c if c.is_whitespace() || c.is_ascii_alphanumeric() => {}
+            _ => return false,
+        }
+        if depth < 0 {
+            return false;
+        }
+    }
+
+    depth == 0 && !in_string
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_shebang() {
+        assert_eq!(
+            Some("python".to_string()),
+            detect_language("#!/usr/bin/env python3\nprint(1)\n")
+        );
+        assert_eq!(
+            Some("bash".to_string()),
+            detect_language("#!/bin/bash\necho\n")
+        );
+        assert_eq!(
+            Some("javascript".to_string()),
+            detect_language("#!/usr/bin/env -S node --harmony\n")
+        );
+    }
+
+    #[test]
+    fn test_detect_modeline() {
+        assert_eq!(
+            Some("lua".to_string()),
+            detect_language("print(1)\n-- vim: set ft=lua:\n")
+        );
+        assert_eq!(
+            Some("python".to_string()),
+            detect_language("# -*- mode: python; coding: utf-8 -*-\n")
+        );
+    }
+
+    #[test]
+    fn test_detect_heuristics() {
+        assert_eq!(
+            Some("json".to_string()),
+            detect_language("{\n  \"a\": [1, 2.5, true]\n}\n")
+        );
+        assert_eq!(
+            Some("go".to_string()),
+            detect_language("package main\n\nfunc main() {}\n")
+        );
+        assert_eq!(
+            Some("python".to_string()),
+            detect_language("def f(x):\n    return x\n")
+        );
+        assert_eq!(None, detect_language("some text\n"));
+    }
+}
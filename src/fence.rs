@@ -0,0 +1,250 @@
+use super::utils;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fence {
+    pub ch: char,
+    pub len: usize,
+}
+
+/// Parses the opening or closing fence of a markdown codeblock. The line
+/// must not contain the container prefix.
+pub fn parse(line: &str) -> Option<Fence> {
+    let line = line.trim_start();
+    let ch = line.chars().next()?;
+    if ch != '`' && ch != '~' {
+        return None;
+    }
+    let len = line.chars().take_while(|c| *c == ch).count();
+    if len < 3 {
+        return None;
+    }
+    Some(Fence { ch, len })
+}
+
+/// Returns the length of the longest line in the text that would close the
+/// fence, if there is one.
+pub fn get_collision(fence: &Fence, text: &str) -> Option<usize> {
+    text.lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let len = line.chars().count();
+            if len >= fence.len && line.chars().all(|c| c == fence.ch) {
+                Some(len)
+            } else {
+                None
+            }
+        })
+        .max()
+}
+
+/// Replaces the fence in the line with a fence of the given length.
+pub fn set_length(line: &str, fence: &Fence, len: usize) -> String {
+    match line.find(fence.ch) {
+        Some(start) => {
+            let end = start + line[start..].chars().take_while(|c| *c == fence.ch).count();
+            format!(
+                "{}{}{}",
+                &line[..start],
+                fence.ch.to_string().repeat(len),
+                &line[end..]
+            )
+        }
+        None => line.to_string(),
+    }
+}
+
+/// Returns the shortest fence that is not closed by any line of the text.
+pub fn get_min_fence(ch: char, text: &str) -> Fence {
+    let len = get_collision(&Fence { ch, len: 3 }, text).map_or(3, |l| l + 1);
+    Fence { ch, len }
+}
+
+/// Rewrites the opening and closing fence of a fenced codeblock to use the
+/// fence character and the shortest fence possible. Whitespace after the info
+/// string is removed. Returns true if the buffer changed.
+pub fn normalize(
+    buf: &mut [String],
+    opening: usize,
+    closing: usize,
+    column: usize,
+    ch: char,
+) -> bool {
+    let line = &buf[opening];
+    let rest = &line[column..];
+    let fence = match parse(rest) {
+        Some(f) => f,
+        None => return false,
+    };
+    let indent = rest.len() - rest.trim_start().len();
+    let info = rest.trim_start()[fence.len..].trim_end();
+
+    // Backticks are not allowed in the info string of a backtick fence
+    let ch = if ch == '`' && info.contains('`') {
+        '~'
+    } else {
+        ch
+    };
+    // Content lines of blockquotes and lists start with the container prefix
+    let prefix = utils::get_container_prefix(&line[..column]);
+    let content = buf[opening + 1..closing]
+        .iter()
+        .map(|l| utils::strip_container_prefix(l, &prefix))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let new_fence = get_min_fence(ch, &content);
+
+    let new_opening = format!(
+        "{}{}{}",
+        &line[..column + indent],
+        new_fence.ch.to_string().repeat(new_fence.len),
+        info
+    );
+    let line = &buf[closing];
+    let new_closing = match line.find(fence.ch) {
+        Some(start) => format!(
+            "{}{}",
+            &line[..start],
+            new_fence.ch.to_string().repeat(new_fence.len)
+        ),
+        None => return false,
+    };
+
+    let changed = buf[opening] != new_opening || buf[closing] != new_closing;
+    buf[opening] = new_opening;
+    buf[closing] = new_closing;
+    changed
+}
+
+/// Turns an indented codeblock into a fenced codeblock. Returns true if the
+/// buffer changed.
+pub fn convert_indented(buf: &mut Vec<String>, start: usize, end: usize, ch: char) -> bool {
+    let prefix = get_indented_prefix(&buf[start..end]);
+    let mut lines = buf[start..end]
+        .iter()
+        .map(|l| strip_code_indent(utils::strip_container_prefix(l, &prefix)).to_string())
+        .collect::<Vec<_>>();
+
+    // Trailing blank lines are part of the node but not of the code
+    while lines.last().is_some_and(|l| l.trim().is_empty()) {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        return false;
+    }
+    let end = start + lines.len();
+    let content = lines.join("\n") + "\n";
+
+    let fence = get_min_fence(ch, &content);
+    let fence = format!("{prefix}{}", fence.ch.to_string().repeat(fence.len));
+    let mut lines = vec![fence.clone()];
+    lines.extend(
+        utils::add_prefix(&content, &prefix)
+            .lines()
+            .map(|l| l.to_string()),
+    );
+    lines.push(fence);
+
+    buf.splice(start..end, lines);
+    true
+}
+
+fn strip_code_indent(line: &str) -> &str {
+    if let Some(l) = line.strip_prefix('\t') {
+        return l;
+    }
+    let spaces = line.len() - line.trim_start_matches(' ').len();
+    &line[spaces.min(4)..]
+}
+
+/// Returns the container prefix of an indented codeblock, the common prefix
+/// of its lines without the four spaces of code indentation.
+fn get_indented_prefix(lines: &[String]) -> String {
+    let mut prefix: Option<String> = None;
+    for line in lines
+        .iter()
+        .filter(|l| !l.trim_start_matches('>').trim().is_empty())
+    {
+        let line_prefix = line
+            .chars()
+            .take_while(|c| *c == '>' || c.is_whitespace())
+            .collect::<String>();
+        prefix = Some(match prefix {
+            Some(p) => p
+                .chars()
+                .zip(line_prefix.chars())
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+            None => line_prefix,
+        });
+    }
+
+    let mut prefix = prefix.unwrap_or_default();
+    if prefix.ends_with('\t') {
+        prefix.pop();
+    } else {
+        for _ in 0..4 {
+            if !prefix.ends_with(' ') {
+                break;
+            }
+            prefix.pop();
+        }
+    }
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Some(Fence { ch: '`', len: 4 }), parse("````python"));
+        assert_eq!(Some(Fence { ch: '~', len: 3 }), parse("  ~~~"));
+        assert_eq!(None, parse("``python"));
+        assert_eq!(None, parse("python"));
+    }
+
+    #[test]
+    fn test_get_collision() {
+        let fence = Fence { ch: '`', len: 3 };
+        assert_eq!(Some(4), get_collision(&fence, "a\n```\n  ````\n"));
+        assert_eq!(None, get_collision(&fence, "```python\n~~~\n``\n"));
+    }
+
+    #[test]
+    fn test_get_min_fence() {
+        assert_eq!(Fence { ch: '`', len: 3 }, get_min_fence('`', "a\n~~~~\n"));
+        assert_eq!(Fence { ch: '`', len: 5 }, get_min_fence('`', "````\n```\n"));
+    }
+
+    #[test]
+    fn test_convert_indented() {
+        let mut buf = vec![
+            "> text".to_string(),
+            ">".to_string(),
+            ">     a".to_string(),
+            ">".to_string(),
+            ">       b".to_string(),
+            ">".to_string(),
+        ];
+        assert!(convert_indented(&mut buf, 2, 6, '`'));
+        assert_eq!(
+            vec!["> text", ">", "> ```", "> a", ">", ">   b", "> ```", ">"],
+            buf
+        );
+    }
+
+    #[test]
+    fn test_normalize_in_blockquote() {
+        let mut buf = ["> ~~~~", "> ```", "> ~~~~"].map(String::from);
+        assert!(normalize(&mut buf, 0, 2, 2, '`'));
+        assert_eq!(["> ````", "> ```", "> ````"].map(String::from), buf);
+    }
+
+    #[test]
+    fn test_set_length() {
+        let fence = Fence { ch: '`', len: 3 };
+        assert_eq!("> ````python", set_length("> ```python", &fence, 4));
+    }
+}
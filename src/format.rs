@@ -1,10 +1,28 @@
-use super::config::Conf;
+use super::batch;
+use super::builtin;
+use super::config::{Batch, Conf, FenceCollision, Language, Session, Wrapper};
+use super::daemon;
+use super::detect;
+use super::fence;
+use super::hidden;
+use super::ignore;
+use super::info;
+use super::join;
+use super::lint;
+use super::org;
+#[cfg(feature = "plugins")]
+use super::plugin;
+use super::position::{self, Rows};
+use super::protect;
+use super::session;
 use super::tree;
 use super::utils;
+use super::wrap;
 use futures::{stream::FuturesOrdered, StreamExt};
 use std::char;
+use std::collections::HashMap;
 use std::fmt;
-use std::io::{self, prelude::*, Error, ErrorKind, Write};
+use std::io::{self, prelude::*, Error, Write};
 use std::process::{Command, Stdio};
 use textwrap::dedent;
 
@@ -14,7 +32,8 @@ pub struct FormatError {
     pub filename: Option<String>,
     pub command: Option<String>,
     pub language: Option<String>,
-    pub start: Option<String>,
+    /// Row of the first line of the codeblock content.
+    pub start: Option<usize>,
 }
 
 impl fmt::Display for FormatError {
@@ -22,8 +41,8 @@ impl fmt::Display for FormatError {
         if let Some(filename) = &self.filename {
             write!(formatter, "{filename}")?;
         }
-        if let Some(start) = &self.start {
-            write!(formatter, "{start}")?;
+        if let Some(start) = self.start {
+            write!(formatter, ":{}", start + 1)?;
         }
         if let Some(language) = &self.language {
             write!(formatter, " [{language}] ->")?;
@@ -39,6 +58,7 @@ pub enum FormatResult {
     Unchanged(String),
     Changed(String),
     Err(FormatError),
+    Lint(Vec<lint::Diagnostic>),
 }
 
 pub async fn run_file(
@@ -46,7 +66,8 @@ pub async fn run_file(
     filename: String,
     parser: Option<&str>,
     write: bool,
-    best_effort: bool,
+    options: RunOptions,
+    worker: &batch::Worker,
 ) -> FormatResult {
     let parser = match utils::get_parser(Some(&filename), parser) {
         Ok(p) => p,
@@ -67,7 +88,11 @@ pub async fn run_file(
     };
     let buf = file.lines().map(|l| l.unwrap()).collect::<Vec<_>>();
 
-    match run(buf, conf, &parser, !write, best_effort).await {
+    let options = RunOptions {
+        fail_fast: !write,
+        ..options
+    };
+    match run(buf, conf, &parser, options, worker).await {
         FormatResult::Changed(r) => {
             if write {
                 if let Some(error) = tokio::fs::write(&filename, r).await.err() {
@@ -87,6 +112,18 @@ pub async fn run_file(
             error.filename = Some(filename);
             FormatResult::Err(error)
         }
+        FormatResult::Lint(diagnostics) if diagnostics.is_empty() => {
+            FormatResult::Unchanged(filename)
+        }
+        FormatResult::Lint(diagnostics) => FormatResult::Lint(
+            diagnostics
+                .into_iter()
+                .map(|d| lint::Diagnostic {
+                    filename: Some(filename.to_owned()),
+                    ..d
+                })
+                .collect(),
+        ),
     }
 }
 
@@ -94,7 +131,8 @@ pub async fn run_stdin(
     conf: &Conf,
     filename: Option<&str>,
     parser: Option<&str>,
-    best_effort: bool,
+    options: RunOptions,
+    worker: &batch::Worker,
 ) -> FormatResult {
     let parser = match utils::get_parser(filename, parser) {
         Ok(p) => p,
@@ -103,7 +141,7 @@ pub async fn run_stdin(
 
     let buf = io::stdin().lines().map(|l| l.unwrap()).collect::<Vec<_>>();
 
-    match run(buf, conf, &parser, false, best_effort).await {
+    match run(buf, conf, &parser, options, worker).await {
         FormatResult::Changed(r) => {
             let mut stdout = io::stdout().lock();
             stdout.write_all(r.as_bytes()).unwrap();
@@ -115,25 +153,84 @@ pub async fn run_stdin(
             FormatResult::Unchanged("stdin".to_string())
         }
         FormatResult::Err(e) => FormatResult::Err(e),
+        FormatResult::Lint(diagnostics) => FormatResult::Lint(
+            diagnostics
+                .into_iter()
+                .map(|d| lint::Diagnostic {
+                    filename: Some(filename.unwrap_or("stdin").to_string()),
+                    ..d
+                })
+                .collect(),
+        ),
     }
 }
 
+/// Options of a single run over a document.
+#[derive(Clone, Copy, Default)]
+pub struct RunOptions {
+    pub fail_fast: bool,
+    pub best_effort: bool,
+    pub annotate: bool,
+    pub lint: bool,
+    /// How deep the document is nested inside codeblocks.
+    pub depth: usize,
+    /// Rows of the file above the document.
+    pub offset: usize,
+}
+
 struct FormatCtx {
     language: String,
+    /// Rows of the file above the document, for documents inside codeblocks.
+    offset: usize,
     codeblock_start: usize,
     start: usize,
     end: usize,
-    input_hash: u64,
+    prefix: String,
+    fence: Option<fence::Fence>,
+}
+
+/// Codeblocks of one language that are formatted together.
+struct JoinedBlocks {
+    language: String,
+    formatter: Vec<String>,
+    options: Language,
+    blocks: Vec<(FormatCtx, String)>,
+}
+
+impl FormatCtx {
+    /// Returns the row of the first line of the content in the file.
+    fn row(&self) -> usize {
+        self.offset + self.start
+    }
+
+    fn error(&self, msg: &str) -> FormatError {
+        FormatError {
+            msg: msg.to_string(),
+            filename: None,
+            command: None,
+            language: Some(self.language.to_owned()),
+            start: Some(self.row()),
+        }
+    }
 }
 
 async fn run(
     mut buf: Vec<String>,
     conf: &Conf,
     parser: &str,
-    fail_fast: bool,
-    best_effort: bool,
+    run_options: RunOptions,
+    worker: &batch::Worker,
 ) -> FormatResult {
-    let src = buf.join("\n");
+    let RunOptions {
+        fail_fast,
+        best_effort,
+        annotate,
+        lint,
+        depth,
+        offset,
+    } = run_options;
+    // The trailing newline makes sure a closing fence on the last line is parsed as one
+    let src = buf.join("\n") + "\n";
     let src_bytes = src.as_bytes();
     let tree = match tree::get_tree(parser, src_bytes) {
         Some(t) => t,
@@ -148,66 +245,325 @@ async fn run(
         }
     };
     let query = tree::get_query(parser).unwrap();
+    let highlights = if parser == "restructuredtext" {
+        tree::get_highlights(&tree, src_bytes)
+    } else {
+        Vec::new()
+    };
+
+    let ignored = ignore::Ignored::new(
+        &buf,
+        parser,
+        conf.markdown.prettier_ignore,
+        &tree::get_content_rows(parser, &tree, src_bytes),
+    );
 
     let mut futures: FuturesOrdered<_> = FuturesOrdered::new();
+    let mut edits: Vec<(usize, usize, usize, String)> = Vec::new();
+    let mut joined: Vec<JoinedBlocks> = Vec::new();
+    let mut checks: FuturesOrdered<_> = FuturesOrdered::new();
+    let mut diagnostics: Vec<lint::Diagnostic> = Vec::new();
 
     let mut cursor = tree_sitter::QueryCursor::new();
     for each_match in cursor.matches(&query, tree.root_node(), src_bytes) {
-        let mut content = String::new();
+        let mut is_directive = false;
+        let mut is_code = true;
+        let mut attributes = HashMap::new();
         let mut ctx = FormatCtx {
             language: String::new(),
+            offset,
             codeblock_start: 0,
             start: 0,
             end: 0,
-            input_hash: 0,
+            prefix: String::new(),
+            fence: None,
         };
+        let mut header_row = 0;
+        let mut directive = String::new();
+        let mut codeblock_column = 0;
+        let mut language_column = None;
+        let mut has_tag = false;
 
         for capture in each_match.captures.iter() {
-            let mut range = capture.node.range();
-
-            for predicate in query.general_predicates(each_match.pattern_index) {
-                range = tree::handle_directive(&predicate.operator, &range, &predicate.args)
-                    .unwrap_or(range);
-            }
-
+            let range = capture.node.range();
             let capture_name = &query.capture_names()[capture.index as usize];
 
             if capture_name == "language" {
-                ctx.language = String::from(&src[range.start_byte..range.end_byte]);
+                let text = &src[range.start_byte..range.end_byte];
+                ctx.language = text.trim().to_string();
+                has_tag = true;
+                language_column =
+                    Some(range.start_point.column + text.len() - text.trim_start().len());
+            }
+            if capture_name == "directive" {
+                directive = String::from(&src[range.start_byte..range.end_byte]);
+            }
+            if capture_name == "info_string" {
+                let text = &src[range.start_byte..range.end_byte];
+                let info = info::parse(text);
+                has_tag = true;
+                language_column =
+                    info::find_language(text, &info.language).map(|p| range.start_point.column + p);
+                ctx.language = info.language;
+                attributes = info.attributes;
+                is_directive = info.directive.is_some();
+                // Admonitions and other MyST directives hold prose, not code
+                is_code = info.directive.is_none_or(|d| info::is_code_directive(&d));
+            }
+            if capture_name == "name" {
+                header_row = range.start_point.row;
+            }
+            if capture_name == "header" {
+                // Header arguments are not part of the node, read until the end of the line
+                let end_byte = src[range.start_byte..]
+                    .find('\n')
+                    .map_or(src.len(), |p| range.start_byte + p);
+                let info = org::parse_header(&src[range.start_byte..end_byte]);
+                ctx.language = info.language;
+                attributes = info.attributes;
+                has_tag = true;
+                language_column = Some(range.start_point.column);
             }
             if capture_name == "content" {
                 ctx.start = range.start_point.row;
                 ctx.end = range.end_point.row;
-                let mut end_byte = range.end_byte;
 
-                // Workaround for bug in markdown parser when the codeblock is the last thing in a
-                // buffer
-                if parser == "markdown" && &src[(end_byte - 3)..end_byte] == "```" {
-                    end_byte -= 3
+                // Restructuredtext content ends after the last character, not on the next line
+                if parser == "restructuredtext" {
+                    ctx.end += 1;
                 }
-
-                content = String::from(dedent(&src[range.start_byte..end_byte]));
             }
             if capture_name == "codeblock" {
                 ctx.codeblock_start = range.start_point.row;
+                codeblock_column = range.start_point.column;
             }
         }
 
-        let formatter = conf.languages.get(&ctx.language);
-        let formatter = match formatter {
-            Some(f) => f,
-            None => continue,
+        if !is_code || ignored.contains(ctx.codeblock_start) {
+            continue;
+        }
+
+        // Content nodes start after the indent, read whole lines and take off the container
+        // prefix (blockquote markers, list indent) instead
+        let container_prefix = if parser == "markdown" {
+            ctx.fence = fence::parse(&buf[ctx.codeblock_start][codeblock_column..]);
+            utils::get_container_prefix(&buf[ctx.codeblock_start][..codeblock_column])
+        } else {
+            String::new()
         };
-        let formatter = formatter.iter().map(|f| f.to_owned()).collect();
+        let mut content: String = buf[ctx.start..ctx.end]
+            .iter()
+            .map(|l| format!("{}\n", utils::strip_container_prefix(l, &container_prefix)))
+            .collect();
+
+        // Directive options are not code, keep them out of the formatted range
+        if is_directive {
+            let option_lines = info::count_directive_option_lines(&content);
+            ctx.start += option_lines;
+            if ctx.start >= ctx.end {
+                continue;
+            }
+            content = content
+                .split_inclusive('\n')
+                .skip(option_lines)
+                .collect::<String>();
+        }
+        if parser == "restructuredtext" {
+            ctx.language = match directive.as_str() {
+                "ipython" => "ipython".to_string(),
+                "doctest" => "pycon".to_string(),
+                _ if ctx.language.is_empty() => highlights
+                    .iter()
+                    .rev()
+                    .find(|(row, _)| *row < ctx.start)
+                    .map_or(String::new(), |(_, l)| l.to_owned()),
+                _ => ctx.language.to_owned(),
+            };
+            if directive == "ipython" || directive == "doctest" {
+                language_column = None;
+            }
+        }
+
+        if parser == "org" {
+            ctx.prefix = org::get_indent(&conf.org, &buf[header_row], &content);
+        } else if parser == "restructuredtext" {
+            // Directive content can be indented relative to the directive, literal blocks keep
+            // their indent
+            ctx.prefix = match (directive.is_empty(), conf.restructuredtext.indent) {
+                (false, Some(indent)) => " ".repeat(codeblock_column + indent),
+                _ => utils::get_common_indent(&content),
+            };
+        } else {
+            ctx.prefix = container_prefix + &utils::get_common_indent(&content);
+        }
+        let content = if parser == "org" && org::preserves_indentation(&conf.org, &buf[header_row])
+        {
+            content
+        } else {
+            dedent(&content)
+        };
+
+        if ctx.language.is_empty() {
+            // Only untagged codeblocks get a detected or default language, a tag without one
+            // (like a MyST directive) says what the content is
+            if has_tag {
+                continue;
+            }
+            let detected = if conf.detect_language {
+                detect::detect_language(&content)
+            } else {
+                None
+            };
+            let language = match detected.or_else(|| conf.default_language.to_owned()) {
+                Some(l) => conf.get_language(&l).to_string(),
+                None => continue,
+            };
+
+            // Literal blocks and directives with a fixed language have no tag to write
+            let tag_row = match parser {
+                "org" => Some(header_row),
+                "restructuredtext"
+                    if !matches!(directive.as_str(), "code" | "code-block" | "sourcecode") =>
+                {
+                    None
+                }
+                _ => Some(ctx.codeblock_start),
+            };
+            if let (true, Some(row)) = (annotate, tag_row) {
+                let line = &buf[row];
+                let column = line.trim_end().len();
+                let separator = if parser == "markdown" { "" } else { " " };
+                edits.push((
+                    row,
+                    column,
+                    line.len() - column,
+                    format!("{separator}{language}"),
+                ));
+            }
+            ctx.language = language;
+        } else {
+            let language = conf.get_language(&ctx.language);
+            if language != ctx.language {
+                if let (true, Some(column)) = (conf.canonicalize_languages, language_column) {
+                    // The language is always on the first line of the codeblock
+                    let row = if parser == "org" {
+                        header_row
+                    } else {
+                        ctx.codeblock_start
+                    };
+                    edits.push((row, column, ctx.language.len(), language.to_string()));
+                }
+                ctx.language = language.to_string();
+            }
+        }
+
+        // Sessions format the input with the formatters of the session language
+        let session = conf.get_session(&ctx.language, &content);
+        let language = session
+            .as_ref()
+            .map_or(ctx.language.to_owned(), |s| s.language.to_owned());
+
+        let formatter = conf.get_formatters(&language, &attributes);
+        let nested_parser = if depth < conf.recursion_depth {
+            tree::get_parser_lang_from_language(&ctx.language)
+        } else {
+            None
+        };
+        let formatter = match (formatter, nested_parser) {
+            (Some(f), _) => f,
+            (None, Some(_)) => Vec::new(),
+            (None, None) => continue,
+        };
+        let options = conf.languages.get(&language).cloned().unwrap_or_default();
+
+        let mut content = if parser == "org" {
+            org::unescape(&content)
+        } else {
+            content
+        };
+
+        // Codeblocks inside documents are formatted before the document itself
+        if let Some(nested_parser) = nested_parser {
+            let lines = content.lines().map(|l| l.to_string()).collect();
+            let nested_options = RunOptions {
+                fail_fast: false,
+                depth: depth + 1,
+                offset: ctx.row(),
+                ..run_options
+            };
+            content = match Box::pin(run(lines, conf, nested_parser, nested_options, worker)).await
+            {
+                FormatResult::Changed(o) | FormatResult::Unchanged(o) => o,
+                FormatResult::Err(e) => return FormatResult::Err(e),
+                FormatResult::Lint(d) => {
+                    diagnostics.extend(d.into_iter().map(|d| lint::Diagnostic {
+                        column: d.column.map(|c| c + ctx.prefix.chars().count()),
+                        ..d
+                    }));
+                    continue;
+                }
+            };
+        }
+
+        // Check commands only read the codeblocks, sessions have no code to check
+        if lint {
+            if !options.check.is_empty() && session.is_none() {
+                checks.push_back(tokio::spawn(async move { check(ctx, options, &content) }));
+            }
+            continue;
+        }
+
+        // Joined codeblocks are formatted together after all codeblocks are read
+        if options.join && session.is_none() && nested_parser.is_none() {
+            match joined
+                .iter_mut()
+                .find(|j| j.language == language && j.formatter == formatter)
+            {
+                Some(j) => j.blocks.push((ctx, content)),
+                None => joined.push(JoinedBlocks {
+                    language,
+                    formatter,
+                    options,
+                    blocks: vec![(ctx, content)],
+                }),
+            }
+            continue;
+        }
 
-        ctx.input_hash = utils::get_hash(&content);
+        let worker = worker.clone();
         futures.push_back(tokio::spawn(async move {
-            format(ctx, formatter, &content).await
+            format(ctx, formatter, options, session, &content, &worker)
+                .await
+                .map(|o| vec![o])
         }));
     }
 
-    let mut formatted = false;
-    let mut offset: i32 = 0;
+    for j in joined {
+        let worker = worker.clone();
+        futures.push_back(tokio::spawn(async move { format_joined(j, &worker).await }));
+    }
+
+    if lint {
+        while let Some(result) = checks.next().await {
+            match result {
+                Ok(d) => diagnostics.extend(d),
+                Err(e) => {
+                    return FormatResult::Err(FormatError {
+                        msg: e.to_string(),
+                        filename: None,
+                        command: None,
+                        language: None,
+                        start: None,
+                    })
+                }
+            }
+        }
+        diagnostics.sort_by_key(|d| (d.line, d.column));
+        return FormatResult::Lint(diagnostics);
+    }
+
+    // Joined codeblocks finish out of order, the buffer is changed from top to bottom
+    let mut results = Vec::new();
     while let Some(output) = futures.next().await {
         let output = match output {
             Ok(o) => o,
@@ -221,43 +577,114 @@ async fn run(
                 });
             }
         };
-        let (ctx, output) = match output {
-            Ok(o) => o,
+        match output {
+            Ok(o) => results.extend(o),
             Err(e) => {
                 if best_effort {
                     continue;
                 }
                 return FormatResult::Err(e);
             }
+        }
+    }
+    results.sort_by_key(|(ctx, _)| ctx.start);
+
+    // Tags are written before the fences are lengthened, their columns are from the tree
+    let mut formatted = false;
+    for (row, column, len, text) in edits {
+        buf[row].replace_range(column..column + len, &text);
+        formatted = true;
+    }
+
+    let mut offset: i32 = 0;
+    let mut splices: Vec<(usize, i32)> = Vec::new();
+    for (ctx, output) in results {
+        let output = if parser == "org" {
+            org::escape(&output)
+        } else {
+            output
         };
 
-        let indent = utils::get_start_whitespace(&buf[(ctx.start as i32 + offset) as usize]);
+        let fixed_output = utils::add_prefix(&output, &ctx.prefix);
 
-        let mut fixed_output = String::new();
-        for line in output.lines() {
-            fixed_output.push_str(&indent);
-            fixed_output.push_str(line);
-            fixed_output.push('\n');
+        let start = (ctx.start as i32 + offset) as usize;
+        let end = (ctx.end as i32 + offset) as usize;
+
+        // A line in the output that closes the fence would end the codeblock early
+        if let Some(fence) = ctx.fence {
+            if let Some(len) = fence::get_collision(&fence, &output) {
+                if conf.markdown.fence_collision == FenceCollision::Error {
+                    if best_effort {
+                        continue;
+                    }
+                    return FormatResult::Err(ctx.error(&format!(
+                        "Formatted output contains a line of {len} '{}' that closes the codeblock.",
+                        fence.ch
+                    )));
+                }
+
+                let opening = (ctx.codeblock_start as i32 + offset) as usize;
+                buf[opening] = fence::set_length(&buf[opening], &fence, len + 1);
+                let closing = buf
+                    .get(end)
+                    .and_then(|l| fence::parse(utils::strip_container_prefix(l, &ctx.prefix)));
+                if closing.map(|f| f.ch) == Some(fence.ch) {
+                    buf[end] = fence::set_length(&buf[end], &fence, len + 1);
+                }
+                formatted = true;
+            }
         }
+        let lines = fixed_output
+            .lines()
+            .map(|l| l.to_string())
+            .collect::<Vec<_>>();
 
-        // trim start for the hash because treesitter ignores leading indent
-        let output_hash = utils::get_hash(fixed_output.trim_start());
-        if ctx.input_hash != output_hash {
+        if buf[start..end] != lines[..] {
             formatted = true;
             if fail_fast {
                 break;
             }
         }
 
-        buf.drain((ctx.start as i32 + offset) as usize..(ctx.end as i32 + offset) as usize);
+        splices.push((ctx.end, lines.len() as i32 - (end - start) as i32));
+        offset += lines.len() as i32 - (end - start) as i32;
+        buf.splice(start..end, lines);
+    }
 
-        let mut counter = 0;
-        for (i, line) in fixed_output.lines().enumerate() {
-            buf.insert(i + (ctx.start as i32 + offset) as usize, line.to_string());
-            counter += 1;
-        }
+    // Rows of the tree are from before formatting, move them by the lines added above them
+    let get_row = |row: usize| {
+        let offset: i32 = splices
+            .iter()
+            .filter(|(end, _)| *end <= row)
+            .map(|(_, o)| o)
+            .sum();
+        (row as i32 + offset) as usize
+    };
+
+    if parser == "markdown" && conf.markdown.normalize_fences {
+        let ch = conf.markdown.fence_style.char();
 
-        offset += counter - (ctx.end as i32 - ctx.start as i32);
+        // Bottom up, so converted indented codeblocks don't move the rows of the ones above
+        for codeblock in tree::get_markdown_codeblocks(&tree).iter().rev() {
+            let start = match *codeblock {
+                tree::MarkdownCodeblock::Fenced { opening, .. } => opening,
+                tree::MarkdownCodeblock::Indented { start, .. } => start,
+            };
+            if ignored.contains(start) {
+                continue;
+            }
+            formatted |= match *codeblock {
+                tree::MarkdownCodeblock::Fenced {
+                    opening,
+                    closing: Some(closing),
+                    column,
+                } => fence::normalize(&mut buf, get_row(opening), get_row(closing), column, ch),
+                tree::MarkdownCodeblock::Fenced { closing: None, .. } => false,
+                tree::MarkdownCodeblock::Indented { start, end } => {
+                    fence::convert_indented(&mut buf, get_row(start), get_row(end), ch)
+                }
+            };
+        }
     }
 
     let output = buf.join("\n") + "\n";
@@ -273,7 +700,7 @@ struct ParsedCommand<'a> {
     args: Vec<&'a str>,
 }
 
-fn parse_command<'a>(raw_command: &'a str) -> Result<ParsedCommand<'a>, &str> {
+fn parse_command<'a>(raw_command: &'a str) -> Result<ParsedCommand<'a>, &'a str> {
     let mut parsed_components = raw_command.split(char::is_whitespace);
     let cmd = parsed_components.next().ok_or("No command found.")?;
     if cmd.is_empty() {
@@ -288,41 +715,278 @@ fn parse_command<'a>(raw_command: &'a str) -> Result<ParsedCommand<'a>, &str> {
 async fn format(
     ctx: FormatCtx,
     formatter: Vec<String>,
+    options: Language,
+    session: Option<Session>,
     content: &str,
+    worker: &batch::Worker,
 ) -> Result<(FormatCtx, String), FormatError> {
+    let result = match &session {
+        Some(s) => {
+            let (ctx, formatter, options) = (&ctx, &formatter, &options);
+            session::format(
+                s,
+                content,
+                |input, row| async move {
+                    let rows = position::get_rows(ctx.row() + row, input.lines().count());
+                    format_code(ctx, &s.language, formatter, options, &input, &rows, worker).await
+                },
+                |msg| ctx.error(&msg),
+            )
+            .await?
+        }
+        None => {
+            let rows = position::get_rows(ctx.row(), content.lines().count());
+            format_code(
+                &ctx,
+                &ctx.language,
+                &formatter,
+                &options,
+                content,
+                &rows,
+                worker,
+            )
+            .await?
+        }
+    };
+    Ok((ctx, result))
+}
+
+async fn format_joined(
+    joined: JoinedBlocks,
+    worker: &batch::Worker,
+) -> Result<Vec<(FormatCtx, String)>, FormatError> {
+    let JoinedBlocks {
+        language,
+        formatter,
+        options,
+        blocks,
+    } = joined;
+    let ctx = &blocks[0].0;
+    let comment = join::get_comment(&language, &options).ok_or_else(|| {
+        ctx.error("Joined codeblocks need the comment of the language for separators.")
+    })?;
+
+    let contents = blocks.iter().map(|(_, c)| c.as_str()).collect::<Vec<_>>();
+    // Separators are not in the document
+    let rows = blocks
+        .iter()
+        .flat_map(|(ctx, c)| {
+            std::iter::once(None).chain(position::get_rows(ctx.row(), c.lines().count()))
+        })
+        .collect::<Rows>();
+    let (joined, marker) = join::join(&contents, comment);
+    let output = format_code(ctx, &language, &formatter, &options, &joined, &rows, worker).await?;
+    let outputs = join::split(&output, comment, &marker, blocks.len()).ok_or_else(|| {
+        ctx.error("Separators of joined codeblocks are missing in the formatted output.")
+    })?;
+
+    Ok(blocks
+        .into_iter()
+        .map(|(ctx, _)| ctx)
+        .zip(outputs)
+        .collect())
+}
+
+async fn format_code(
+    ctx: &FormatCtx,
+    language: &str,
+    formatter: &[String],
+    options: &Language,
+    content: &str,
+    rows: &[Option<usize>],
+    worker: &batch::Worker,
+) -> Result<String, FormatError> {
+    let (content, placeholders) = match protect::get_regex(&options.protect) {
+        Ok(Some(regex)) => protect::protect(content, &regex),
+        Ok(None) => (content.to_string(), Vec::new()),
+        Err(e) => return Err(ctx.error(&e.to_string())),
+    };
+
+    let marker = hidden::get_marker(language, options);
+    let (content, lines) = match marker {
+        Some(m) => hidden::unhide(&content, m),
+        None => (content.to_string(), Vec::new()),
+    };
+
+    // The batch command replaces the formatters of the language, not the ones of rules
+    let batch = options
+        .batch
+        .as_ref()
+        .filter(|_| formatter == options.formatters)
+        .map(|b| (worker, b));
+    let result = format_wrapped(ctx, formatter, batch, &options.wrappers, &content, rows).await?;
+
+    let result = match marker {
+        Some(m) if lines.iter().any(|(_, hidden)| *hidden) => hidden::rehide(&result, m, &lines)
+            .ok_or_else(|| ctx.error("Hidden lines are missing in the formatted output."))?,
+        _ => result,
+    };
+
+    let result = protect::restore(&result, &placeholders).map_err(|text| {
+        ctx.error(&format!(
+            "The placeholder for \"{text}\" is missing or duplicated in the formatted output."
+        ))
+    })?;
+    Ok(result)
+}
+
+async fn format_wrapped(
+    ctx: &FormatCtx,
+    formatter: &[String],
+    batch: Option<(&batch::Worker, &Batch)>,
+    wrappers: &[Wrapper],
+    content: &str,
+    rows: &[Option<usize>],
+) -> Result<String, FormatError> {
+    if wrappers.is_empty() {
+        return format_all(ctx, formatter, batch, content, rows).await;
+    }
+
+    // Try the wrappers in order, the error of the last one is returned if none works
+    let mut error = None;
+    for wrapper in wrappers.iter() {
+        let rows = std::iter::repeat_n(None, wrapper.prefix.lines().count())
+            .chain(rows.iter().copied())
+            .collect::<Rows>();
+        let result = format_all(ctx, formatter, batch, &wrap::wrap(wrapper, content), &rows)
+            .await
+            .and_then(|o| {
+            wrap::unwrap(wrapper, &o).ok_or_else(|| {
+                ctx.error("Formatted output does not start with the wrapper prefix and end with the wrapper suffix.")
+            })
+        });
+        match result {
+            Ok(o) => return Ok(o),
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error.unwrap())
+}
+
+async fn format_all(
+    ctx: &FormatCtx,
+    formatter: &[String],
+    batch: Option<(&batch::Worker, &Batch)>,
+    content: &str,
+    rows: &[Option<usize>],
+) -> Result<String, FormatError> {
     let mut result = String::from(content);
-    let language = Some(ctx.language.to_owned());
-    let start = Some(format!(":{}", ctx.start));
 
-    for f in formatter.iter() {
+    // Lines in messages of later formatters are from output of the ones before, they are not
+    // moved to the document
+    let error = |msg: String, command: Option<String>, first: bool| {
+        let (mut msg, row) = if first {
+            position::map_lines(&msg, rows, ctx.prefix.chars().count())
+        } else {
+            (msg, None)
+        };
+        let excerpt = position::get_excerpt(content, rows, row);
+        if !excerpt.is_empty() {
+            if !msg.ends_with('\n') {
+                msg.push('\n');
+            }
+            msg.push_str(&excerpt);
+        }
+        FormatError {
+            msg,
+            filename: None,
+            command,
+            language: Some(ctx.language.to_owned()),
+            start: Some(ctx.row()),
+        }
+    };
+
+    if let Some((worker, batch)) = batch {
+        return worker
+            .format(batch, content)
+            .await
+            .map_err(|msg| error(msg, Some(batch.command.to_owned()), true));
+    }
+
+    for (i, f) in formatter.iter().enumerate() {
+        if let Some(name) = f.strip_prefix(builtin::PREFIX) {
+            result = builtin::format(name, &result)
+                .map_err(|msg| error(msg, Some(f.to_owned()), i == 0))?;
+            continue;
+        }
+        #[cfg(feature = "plugins")]
+        if let Some(name) = f.strip_prefix(plugin::PREFIX) {
+            let (name, input) = (name.to_owned(), result);
+            result = tokio::task::spawn_blocking(move || plugin::format(&name, &input))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|r| r)
+                .map_err(|msg| error(msg, Some(f.to_owned()), i == 0))?;
+            continue;
+        }
+        if let Some(name) = f.strip_prefix(daemon::PREFIX) {
+            let (name, lang, input) = (name.to_owned(), ctx.language.to_owned(), result);
+            result = tokio::task::spawn_blocking(move || daemon::format(&name, &lang, &input))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|r| r)
+                .map_err(|msg| error(msg, Some(f.to_owned()), i == 0))?;
+            continue;
+        }
         match parse_command(f) {
             Ok(parsed_command) => {
-                result = match format_single(&parsed_command, &result) {
-                    Err(e) => {
-                        return Err(FormatError {
-                            msg: e.to_string(),
-                            filename: None,
-                            command: Some(parsed_command.cmd.to_string()),
-                            language,
-                            start,
-                        });
-                    }
-                    Ok(o) => o,
-                }
+                result = format_single(&parsed_command, &result).map_err(|e| {
+                    error(e.to_string(), Some(parsed_command.cmd.to_string()), i == 0)
+                })?;
             }
-            Err(msg) => {
-                return Err(FormatError {
-                    msg: msg.to_owned(),
-                    filename: None,
-                    command: None,
-                    language,
-                    start,
-                })
+            Err(msg) => return Err(error(msg.to_owned(), None, false)),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Runs the check commands of the language, the diagnostics are moved to the
+/// position of the codeblock in the document.
+fn check(ctx: FormatCtx, options: Language, content: &str) -> Vec<lint::Diagnostic> {
+    let content = match hidden::get_marker(&ctx.language, &options) {
+        Some(m) => hidden::unhide(content, m).0,
+        None => content.to_string(),
+    };
+    let indent = ctx.prefix.chars().count();
+
+    let mut result = Vec::new();
+    for command in options.check.iter() {
+        let output = parse_command(command)
+            .map_err(|e| e.to_string())
+            .and_then(|c| check_single(&c, &content).map_err(|e| e.to_string()));
+        match output {
+            Ok((success, output)) => {
+                result.extend(lint::parse(&output, success, ctx.row(), indent));
             }
+            Err(msg) => result.push(lint::Diagnostic {
+                filename: None,
+                line: ctx.row() + 1,
+                column: None,
+                message: format!("{command}: {msg}"),
+            }),
         }
     }
+    result
+}
 
-    Ok((ctx, result))
+fn check_single(command: &ParsedCommand, input: &str) -> Result<(bool, String), Error> {
+    let mut child = Command::new(command.cmd)
+        .args(&command.args)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let stdin = child
+        .stdin
+        .as_mut()
+        .ok_or_else(|| Error::other(String::from("Child process stdin has not been captured.")))?;
+    stdin.write_all(input.as_bytes())?;
+
+    let output = child.wait_with_output()?;
+    let text = String::from_utf8_lossy(&output.stdout) + String::from_utf8_lossy(&output.stderr);
+    Ok((output.status.success(), text.to_string()))
 }
 
 fn format_single(formatter: &ParsedCommand, input: &str) -> Result<String, Error> {
@@ -333,12 +997,10 @@ fn format_single(formatter: &ParsedCommand, input: &str) -> Result<String, Error
         .stdout(Stdio::piped())
         .spawn()?;
 
-    let stdin = child.stdin.as_mut().ok_or_else(|| {
-        Error::new(
-            ErrorKind::Other,
-            String::from("Child process stdin has not been captured."),
-        )
-    })?;
+    let stdin = child
+        .stdin
+        .as_mut()
+        .ok_or_else(|| Error::other(String::from("Child process stdin has not been captured.")))?;
     stdin.write_all(input.as_bytes())?;
 
     let output = child.wait_with_output()?;
@@ -346,10 +1008,7 @@ fn format_single(formatter: &ParsedCommand, input: &str) -> Result<String, Error
     if output.status.success() {
         Ok(String::from_utf8(output.stdout).unwrap())
     } else {
-        Err(Error::new(
-            ErrorKind::Other,
-            String::from_utf8(output.stderr).unwrap(),
-        ))
+        Err(Error::other(String::from_utf8(output.stderr).unwrap()))
     }
 }
 
@@ -373,4 +1032,211 @@ mod tests {
             parse_command("shellharden --transform ")
         );
     }
+
+    async fn run_markdown(conf: &str, src: &str, annotate: bool) -> String {
+        run_document("markdown", conf, src, annotate).await
+    }
+
+    async fn run_document(parser: &str, conf: &str, src: &str, annotate: bool) -> String {
+        let conf: Conf = toml::from_str(conf).unwrap();
+        let buf = src.lines().map(|l| l.to_string()).collect();
+        let options = RunOptions {
+            annotate,
+            ..Default::default()
+        };
+        match run(buf, &conf, parser, options, &batch::Worker::start()).await {
+            FormatResult::Changed(o) | FormatResult::Unchanged(o) => o,
+            _ => panic!("Formatting failed."),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nested_document() {
+        let src = "````markdown\n```python\nx\n```\n````\n\ntext";
+        assert_eq!(
+            "````markdown\n```python\ny\n```\n````\n\ntext\n",
+            run_markdown(
+                "recursion_depth = 1\n[languages]\npython = [\"sed s/x/y/\"]",
+                src,
+                false
+            )
+            .await
+        );
+        assert_eq!(
+            format!("{src}\n"),
+            run_markdown("[languages]\npython = [\"sed s/x/y/\"]", src, false).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recursion_depth() {
+        let src = "`````markdown\n````markdown\n```python\nx\n```\n````\n`````\n\ntext";
+        assert_eq!(
+            format!("{src}\n"),
+            run_markdown(
+                "recursion_depth = 1\n[languages]\npython = [\"sed s/x/y/\"]",
+                src,
+                false
+            )
+            .await
+        );
+        assert_eq!(
+            "`````markdown\n````markdown\n```python\ny\n```\n````\n`````\n\ntext\n",
+            run_markdown(
+                "recursion_depth = 2\n[languages]\npython = [\"sed s/x/y/\"]",
+                src,
+                false
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tag_edits_with_lengthened_fence() {
+        let conf = r#"
+            canonicalize_languages = true
+            default_language = "bt"
+            [aliases]
+            b = "bt"
+            [languages]
+            bt = ["sed s/^x$/```/"]
+        "#;
+        assert_eq!(
+            "````bt\n```\n````\n",
+            run_markdown(conf, "```b\nx\n```\n", false).await
+        );
+        assert_eq!(
+            "````bt\n```\n````\n",
+            run_markdown(conf, "```\nx\n```\n", true).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_in_nested_document() {
+        let conf: Conf =
+            toml::from_str("recursion_depth = 1\n[languages]\npython = [\"false\"]").unwrap();
+        let src = "text\n\n```markdown\n# a\n\n```python\nx\n```\n```\n";
+        let buf = src.lines().map(|l| l.to_string()).collect();
+        let worker = batch::Worker::start();
+        match run(buf, &conf, "markdown", RunOptions::default(), &worker).await {
+            FormatResult::Err(e) => {
+                assert_eq!(Some(6), e.start);
+                assert!(e.msg.ends_with("7 | x\n"));
+            }
+            _ => panic!("Formatting did not fail."),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ignore_marker_in_codeblock() {
+        let conf = "[languages]\npython = [\"sed s/x/y/\"]";
+        assert_eq!(
+            "```markdown\n<!-- cbfmt-off -->\n```\n\n```python\ny\n```\n",
+            run_markdown(
+                conf,
+                "```markdown\n<!-- cbfmt-off -->\n```\n\n```python\nx\n```\n",
+                false
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_default_language_only_for_untagged() {
+        let conf = "default_language = \"python\"\n[languages]\npython = [\"sed s/x/y/\"]";
+        assert_eq!(
+            "```{note}\nx\n```\n\n```\ny\n```\n",
+            run_markdown(conf, "```{note}\nx\n```\n\n```\nx\n```\n", false).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_skip_prose_directive() {
+        let conf = "[languages]\npython = [\"sed s/x/y/\"]";
+        assert_eq!(
+            "```{note} python\nx\n```\n\n```{code-cell} python\ny\n```\n",
+            run_markdown(
+                conf,
+                "```{note} python\nx\n```\n\n```{code-cell} python\nx\n```\n",
+                false
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rst_directives() {
+        let conf = "[languages]\npython = [\"sed s/x/y/\"]";
+        for directive in ["code", "code-block", "sourcecode"] {
+            assert_eq!(
+                format!("Text\n\n.. {directive}:: python\n\n   y = 1\n\nText\n"),
+                run_document(
+                    "restructuredtext",
+                    conf,
+                    &format!("Text\n\n.. {directive}:: python\n\n   x = 1\n\nText\n"),
+                    false
+                )
+                .await
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rst_directive_options() {
+        let conf = "[languages]\npython = [\"sed s/^/#/\"]";
+        assert_eq!(
+            ".. code-block:: python\n   :linenos:\n   :emphasize-lines: 1\n\n   #x = 1\n",
+            run_document(
+                "restructuredtext",
+                conf,
+                ".. code-block:: python\n   :linenos:\n   :emphasize-lines: 1\n\n   x = 1\n",
+                false
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rst_literal_blocks() {
+        let conf = "[languages]\npython = [\"sed s/x/y/\"]";
+        assert_eq!(
+            "Text::\n\n   x = 1\n",
+            run_document("restructuredtext", conf, "Text::\n\n   x = 1\n", false).await
+        );
+        assert_eq!(
+            ".. highlight:: python\n\nText::\n\n   y = 1\n\n::\n\n   y = 2\n",
+            run_document(
+                "restructuredtext",
+                conf,
+                ".. highlight:: python\n\nText::\n\n   x = 1\n\n::\n\n   x = 2\n",
+                false
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rst_directive_indent() {
+        let src = "- item\n\n  .. code-block:: python\n\n      x = 1\n\n      x = 2\n";
+        assert_eq!(
+            "- item\n\n  .. code-block:: python\n\n      y = 1\n\n      y = 2\n",
+            run_document(
+                "restructuredtext",
+                "[languages]\npython = [\"sed s/x/y/\"]",
+                src,
+                false
+            )
+            .await
+        );
+        assert_eq!(
+            "- item\n\n  .. code-block:: python\n\n     y = 1\n\n     y = 2\n",
+            run_document(
+                "restructuredtext",
+                "[restructuredtext]\nindent = 3\n[languages]\npython = [\"sed s/x/y/\"]",
+                src,
+                false
+            )
+            .await
+        );
+    }
 }
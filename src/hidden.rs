@@ -0,0 +1,218 @@
+use super::config::Language;
+
+/// Returns the marker of hidden lines for a language. Rust uses the rustdoc
+/// marker `#` unless it is configured otherwise, an empty marker turns it off.
+pub fn get_marker<'a>(language: &str, options: &'a Language) -> Option<&'a str> {
+    match options.hidden_lines.as_deref() {
+        Some("") => None,
+        Some(m) => Some(m),
+        None if language == "rust" => Some("#"),
+        None => None,
+    }
+}
+
+/// A non-blank line of the content, without whitespace, and if it was hidden.
+pub type Line = (String, bool);
+
+/// Removes the marker from hidden lines, `# let x = 1;` becomes `let x = 1;`.
+/// Returns the content and its lines.
+pub fn unhide(content: &str, marker: &str) -> (String, Vec<Line>) {
+    let mut result = String::new();
+    let mut lines = Vec::new();
+    for line in content.lines() {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        let text = match trimmed.strip_prefix(marker) {
+            Some("") => Some(""),
+            Some(t) => t.strip_prefix(' '),
+            None => None,
+        };
+        let (text, hidden) = match text {
+            Some(t) => (format!("{indent}{t}"), true),
+            None => (line.to_string(), false),
+        };
+        if !text.trim().is_empty() {
+            lines.push((squash(&text), hidden));
+        }
+        result.push_str(&text);
+        result.push('\n');
+    }
+    (result, lines)
+}
+
+/// Adds the marker back to the lines that were hidden. Returns None if a hidden
+/// line can not be found in the output.
+pub fn rehide(output: &str, marker: &str, lines: &[Line]) -> Option<String> {
+    let output_lines = output.lines().collect::<Vec<_>>();
+    let keys = output_lines
+        .iter()
+        .map(|l| squash(l))
+        .filter(|k| !k.is_empty())
+        .collect::<Vec<_>>();
+    let hidden = match_lines(lines, &keys)?;
+
+    let mut hidden = hidden.into_iter();
+    let mut flags = output_lines
+        .iter()
+        .map(|l| {
+            if l.trim().is_empty() {
+                None
+            } else {
+                hidden.next()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Blank lines are hidden when the lines around them are
+    for i in 0..flags.len() {
+        if flags[i].is_none() {
+            let previous = flags[..i].iter().rev().flatten().next();
+            let next = flags[i + 1..].iter().flatten().next();
+            flags[i] = Some(previous == Some(&true) && next == Some(&true));
+        }
+    }
+
+    let mut result = String::new();
+    for (line, hidden) in output_lines.iter().zip(flags) {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        match (hidden, trimmed.is_empty()) {
+            (Some(true), true) => result.push_str(marker),
+            (Some(true), false) => result.push_str(&format!("{indent}{marker} {trimmed}")),
+            _ => result.push_str(line),
+        }
+        result.push('\n');
+    }
+    Some(result)
+}
+
+/// Finds the line of the content each output line comes from. Lines in the
+/// same order are matched first, then lines the formatter moved, like sorted
+/// imports. Lines that were split or changed are hidden if all lines between
+/// the surrounding matches were.
+fn match_lines(lines: &[Line], keys: &[String]) -> Option<Vec<bool>> {
+    let n = lines.len();
+    let m = keys.len();
+
+    // Longest common subsequence, read from the end so a closing line matches the
+    // last candidate
+    let mut table = vec![vec![0; m + 1]; n + 1];
+    for i in 1..=n {
+        for j in 1..=m {
+            table[i][j] = if lines[i - 1].0 == keys[j - 1] {
+                table[i - 1][j - 1] + 1
+            } else {
+                table[i - 1][j].max(table[i][j - 1])
+            };
+        }
+    }
+    let mut anchors = Vec::new();
+    let (mut i, mut j) = (n, m);
+    while i > 0 && j > 0 {
+        if lines[i - 1].0 == keys[j - 1] {
+            anchors.push((i - 1, j - 1));
+            i -= 1;
+            j -= 1;
+        } else if table[i - 1][j] >= table[i][j - 1] {
+            i -= 1;
+        } else {
+            j -= 1;
+        }
+    }
+    anchors.reverse();
+
+    let mut used = vec![false; n];
+    let mut result = vec![None; m];
+    for &(i, j) in anchors.iter() {
+        used[i] = true;
+        result[j] = Some(lines[i].1);
+    }
+
+    // Moved lines
+    for j in 0..m {
+        if result[j].is_none() {
+            if let Some(i) = (0..n).find(|&i| !used[i] && lines[i].0 == keys[j]) {
+                used[i] = true;
+                result[j] = Some(lines[i].1);
+            }
+        }
+    }
+
+    // Changed lines
+    for (j, hidden) in result.iter_mut().enumerate() {
+        if hidden.is_some() {
+            continue;
+        }
+        let start = anchors
+            .iter()
+            .rev()
+            .find(|(_, aj)| *aj < j)
+            .map_or(0, |(ai, _)| ai + 1);
+        let end = anchors
+            .iter()
+            .find(|(_, aj)| *aj > j)
+            .map_or(n, |(ai, _)| *ai);
+        let region = (start..end).filter(|&i| !used[i]).collect::<Vec<_>>();
+        *hidden = Some(!region.is_empty() && region.iter().all(|&i| lines[i].1));
+        for &i in region.iter() {
+            used[i] = true;
+        }
+    }
+
+    if lines
+        .iter()
+        .zip(used)
+        .any(|((_, hidden), used)| *hidden && !used)
+    {
+        return None;
+    }
+    result.into_iter().collect()
+}
+
+fn squash(text: &str) -> String {
+    text.split_whitespace().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hidden_roundtrip() {
+        let content = "# use std::io;\n#\n# fn main() {\nif x {y}\n# }\n## not hidden\n";
+        let (unhidden, lines) = unhide(content, "#");
+        assert_eq!(
+            "use std::io;\n\nfn main() {\nif x {y}\n}\n## not hidden\n",
+            unhidden
+        );
+
+        let output =
+            "use std::io;\n\nfn main() {\n    if x {\n        y\n    }\n}\n## not hidden\n";
+        assert_eq!(
+            Some(
+                "# use std::io;\n#\n# fn main() {\n    if x {\n        y\n    }\n# }\n## not hidden\n"
+                    .to_string()
+            ),
+            rehide(output, "#", &lines)
+        );
+    }
+
+    #[test]
+    fn test_rehide_moved() {
+        let (_, lines) = unhide("# use b;\n# use a;\nlet x = 1;\n", "#");
+        assert_eq!(
+            Some("# use a;\n# use b;\nlet x = 1;\n".to_string()),
+            rehide("use a;\nuse b;\nlet x = 1;\n", "#", &lines)
+        );
+    }
+
+    #[test]
+    fn test_rehide_changed() {
+        let (_, lines) = unhide("# let x = 1;\nlet y = 1;\n", "#");
+        assert_eq!(
+            Some("# let x = 2;\nlet y = 1;\n".to_string()),
+            rehide("let x = 2;\nlet y = 1;\n", "#", &lines)
+        );
+        assert_eq!(None, rehide("let y = 1;\n", "#", &lines));
+    }
+}
@@ -0,0 +1,148 @@
+use std::ops::Range;
+
+#[derive(Debug, PartialEq)]
+enum Marker {
+    Ignore,
+    Off,
+    On,
+}
+
+/// Rows of a document that are excluded from formatting by ignore markers.
+///
+/// - markdown: `<!-- cbfmt-ignore -->` and `<!-- cbfmt-off -->` / `<!-- cbfmt-on -->`
+/// - org: `# cbfmt: ignore` and `# cbfmt: off` / `# cbfmt: on`
+/// - restructuredtext: `.. cbfmt-ignore` and `.. cbfmt-off` / `.. cbfmt-on`
+///
+/// Markers in the content of codeblocks are code, not markers.
+pub struct Ignored {
+    rows: Vec<bool>,
+}
+
+impl Ignored {
+    pub fn new(buf: &[String], parser: &str, prettier: bool, content: &[Range<usize>]) -> Self {
+        let mut rows = vec![false; buf.len()];
+        let mut off = false;
+        let mut ignore_next = false;
+
+        for (row, line) in buf.iter().enumerate() {
+            let marker = if content.iter().any(|r| r.contains(&row)) {
+                None
+            } else {
+                get_marker(line, parser, prettier)
+            };
+            match marker {
+                Some(Marker::Off) => off = true,
+                Some(Marker::On) => off = false,
+                Some(Marker::Ignore) => ignore_next = true,
+                None => {
+                    // The ignore marker applies to the next block, blank lines can be in
+                    // between. Org keywords like `#+name:` are part of the block
+                    if ignore_next && !is_blank(line) {
+                        rows[row] = true;
+                        ignore_next = is_org_keyword(line, parser);
+                    }
+                }
+            }
+            rows[row] |= off;
+        }
+
+        Ignored { rows }
+    }
+
+    /// Returns true if the codeblock starting at the row is ignored.
+    pub fn contains(&self, row: usize) -> bool {
+        self.rows.get(row).copied().unwrap_or(false)
+    }
+}
+
+fn get_marker(line: &str, parser: &str, prettier: bool) -> Option<Marker> {
+    let line = line.trim_start_matches(|c: char| c == '>' || c.is_whitespace());
+    let line = line.trim_end();
+    match parser {
+        "markdown" => {
+            let text = line.strip_prefix("<!--")?.strip_suffix("-->")?.trim();
+            match text {
+                "cbfmt-ignore" => Some(Marker::Ignore),
+                "cbfmt-off" => Some(Marker::Off),
+                "cbfmt-on" => Some(Marker::On),
+                "prettier-ignore" if prettier => Some(Marker::Ignore),
+                "prettier-ignore-start" if prettier => Some(Marker::Off),
+                "prettier-ignore-end" if prettier => Some(Marker::On),
+                _ => None,
+            }
+        }
+        "org" => match line.strip_prefix("# ")?.strip_prefix("cbfmt:")?.trim() {
+            "ignore" => Some(Marker::Ignore),
+            "off" => Some(Marker::Off),
+            "on" => Some(Marker::On),
+            _ => None,
+        },
+        "restructuredtext" => match line.strip_prefix("..")?.trim() {
+            "cbfmt-ignore" => Some(Marker::Ignore),
+            "cbfmt-off" => Some(Marker::Off),
+            "cbfmt-on" => Some(Marker::On),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_blank(line: &str) -> bool {
+    line.trim_start_matches('>').trim().is_empty()
+}
+
+fn is_org_keyword(line: &str, parser: &str) -> bool {
+    let line = line.trim_start().to_lowercase();
+    parser == "org" && line.starts_with("#+") && !line.starts_with("#+begin")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_buf(text: &str) -> Vec<String> {
+        text.lines().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn test_ignore_markdown() {
+        let buf = to_buf(
+            "<!-- cbfmt-ignore -->\n\n```\n```\n<!-- cbfmt-off -->\n```\n```\n<!-- cbfmt-on -->\n```\n",
+        );
+        let ignored = Ignored::new(&buf, "markdown", false, &[]);
+        assert!(ignored.contains(2));
+        assert!(!ignored.contains(3));
+        assert!(ignored.contains(5));
+        assert!(!ignored.contains(8));
+    }
+
+    #[test]
+    fn test_ignore_prettier() {
+        let buf = to_buf("> <!-- prettier-ignore -->\n> ```\n");
+        assert!(!Ignored::new(&buf, "markdown", false, &[]).contains(1));
+        assert!(Ignored::new(&buf, "markdown", true, &[]).contains(1));
+    }
+
+    #[test]
+    fn test_ignore_org_and_rst() {
+        let buf = to_buf("# cbfmt: ignore\n#+name: example\n#+begin_src python\n#+end_src\n");
+        let ignored = Ignored::new(&buf, "org", false, &[]);
+        assert!(ignored.contains(1));
+        assert!(ignored.contains(2));
+        assert!(!ignored.contains(3));
+
+        let buf = to_buf(".. cbfmt-ignore\n\n.. code-block:: python\n");
+        assert!(Ignored::new(&buf, "restructuredtext", false, &[]).contains(2));
+    }
+
+    #[test]
+    fn test_ignore_marker_in_codeblock() {
+        let buf = to_buf("```markdown\n<!-- cbfmt-off -->\n```\n```python\n```\n");
+        assert!(Ignored::new(&buf, "markdown", false, &[]).contains(3));
+        assert!(!Ignored::new(&buf, "markdown", false, &[1..2, 4..4]).contains(3));
+
+        let buf =
+            to_buf("#+begin_src org\n# cbfmt: off\n#+end_src\n#+begin_src python\n#+end_src\n");
+        assert!(!Ignored::new(&buf, "org", false, &[1..2, 4..4]).contains(3));
+    }
+}
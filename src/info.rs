@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+
+/// Doc comment attributes that make rustdoc treat an untagged codeblock as rust.
+const RUSTDOC_ATTRIBUTES: [&str; 7] = [
+    "ignore",
+    "should_panic",
+    "no_run",
+    "compile_fail",
+    "test_harness",
+    "allow_fail",
+    "standalone_crate",
+];
+
+/// MyST directives with code in their body, other directives hold prose.
+const MYST_CODE_DIRECTIVES: [&str; 4] = ["code", "code-block", "sourcecode", "code-cell"];
+
+#[derive(Debug, Default, PartialEq)]
+pub struct InfoString {
+    pub language: String,
+    pub directive: Option<String>,
+    pub attributes: HashMap<String, String>,
+}
+
+/// Parses the info string of a fenced codeblock.
+///
+/// Supported styles are MyST directives (`{code-block} python`), pandoc
+/// attributes (`{.python .numberLines startFrom="10"}`), GitHub and mkdocs
+/// (`python title="main.py"`), and rustdoc (`rust,ignore`).
+/// Classes and flags without a value are stored as attributes with an empty value.
+pub fn parse(text: &str) -> InfoString {
+    let text = text.trim();
+
+    // MyST directive fences, ```{code-block} python
+    if let Some(rest) = text.strip_prefix('{') {
+        if let Some((directive, argument)) = rest.split_once('}') {
+            if !is_attribute_list(directive) {
+                return InfoString {
+                    language: argument.split_whitespace().next().unwrap_or("").to_string(),
+                    directive: Some(directive.trim().to_string()),
+                    attributes: HashMap::new(),
+                };
+            }
+        }
+    }
+
+    let mut info = InfoString::default();
+    for token in tokenize(text) {
+        if let Some(class) = token.strip_prefix('.') {
+            if info.language.is_empty() {
+                info.language = class.to_string();
+            } else {
+                info.attributes.insert(class.to_string(), String::new());
+            }
+        } else if let Some(id) = token.strip_prefix('#') {
+            info.attributes.insert("id".to_string(), id.to_string());
+        } else if let Some((key, value)) = token.split_once('=') {
+            info.attributes.insert(key.to_string(), value.to_string());
+        } else if info.language.is_empty() && !is_rustdoc_attribute(&token) {
+            info.language = token;
+        } else {
+            info.attributes.insert(token, String::new());
+        }
+    }
+
+    if info.language.is_empty() && info.attributes.keys().any(|k| is_rustdoc_attribute(k)) {
+        info.language = "rust".to_string();
+    }
+
+    info
+}
+
+/// Returns the byte position of the language in the info string.
+pub fn find_language(text: &str, language: &str) -> Option<usize> {
+    if language.is_empty() {
+        return None;
+    }
+    let is_separator = |c: char| c.is_whitespace() || matches!(c, '.' | ',' | '{' | '}');
+    text.match_indices(language).map(|(i, _)| i).find(|&i| {
+        let end = i + language.len();
+        text[..i].chars().next_back().is_none_or(is_separator)
+            && text[end..].chars().next().is_none_or(is_separator)
+    })
+}
+
+/// Returns true if the body of the MyST directive is code.
+pub fn is_code_directive(directive: &str) -> bool {
+    MYST_CODE_DIRECTIVES.contains(&directive)
+}
+
+fn is_attribute_list(text: &str) -> bool {
+    match text.split_whitespace().next() {
+        Some(token) => token.starts_with('.') || token.starts_with('#') || token.contains('='),
+        None => false,
+    }
+}
+
+fn is_rustdoc_attribute(token: &str) -> bool {
+    RUSTDOC_ATTRIBUTES.contains(&token) || token.starts_with("edition20")
+}
+
+/// Splits an info string on whitespace, commas and braces. Quotes group a
+/// value and are removed.
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quote = None;
+
+    for ch in text.chars() {
+        match quote {
+            Some(q) if ch == q => quote = None,
+            Some(_) => token.push(ch),
+            None if ch == '"' || ch == '\'' => quote = Some(ch),
+            None if ch.is_whitespace() || ch == ',' || ch == '{' || ch == '}' => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+            }
+            None => token.push(ch),
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+
+    tokens
+}
+
+/// Returns the number of leading lines in a directive body that are options
+/// and not code. Both `:key: value` lines and a `---` delimited YAML block
+/// are options. Blank lines directly after the options are counted as well.
+pub fn count_directive_option_lines(content: &str) -> usize {
+    let lines = content.lines().collect::<Vec<_>>();
+    let mut count = 0;
+
+    if lines.first().map(|l| l.trim()) == Some("---") {
+        match lines.iter().skip(1).position(|l| l.trim() == "---") {
+            Some(p) => count = p + 2,
+            None => return 0,
+        }
+    } else {
+        while count < lines.len() && is_directive_option(lines[count]) {
+            count += 1;
+        }
+    }
+
+    if count == 0 {
+        return 0;
+    }
+    while count < lines.len() && lines[count].trim().is_empty() {
+        count += 1;
+    }
+
+    count
+}
+
+fn is_directive_option(line: &str) -> bool {
+    let line = line.trim();
+    match line.strip_prefix(':') {
+        Some(rest) => match rest.find(':') {
+            Some(p) => p > 0 && !rest[..p].contains(char::is_whitespace),
+            None => false,
+        },
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(attributes: &[(&str, &str)]) -> HashMap<String, String> {
+        attributes
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_directive() {
+        assert_eq!(
+            InfoString {
+                language: "python".to_string(),
+                directive: Some("code-block".to_string()),
+                attributes: HashMap::new(),
+            },
+            parse("{code-block} python")
+        );
+        assert_eq!("", parse("{code-block}").language);
+    }
+
+    #[test]
+    fn test_is_code_directive() {
+        assert!(is_code_directive("code-block"));
+        assert!(is_code_directive("code-cell"));
+        assert!(!is_code_directive("note"));
+        assert!(!is_code_directive("admonition"));
+    }
+
+    #[test]
+    fn test_parse_pandoc() {
+        let info = parse(r#"{#example .python .numberLines startFrom="10"}"#);
+        assert_eq!("python", info.language);
+        assert_eq!(None, info.directive);
+        assert_eq!(
+            attributes(&[("id", "example"), ("numberLines", ""), ("startFrom", "10")]),
+            info.attributes
+        );
+    }
+
+    #[test]
+    fn test_parse_github_and_mkdocs() {
+        let info = parse(r#"python title="main.py" hl_lines="2 3""#);
+        assert_eq!("python", info.language);
+        assert_eq!(
+            attributes(&[("title", "main.py"), ("hl_lines", "2 3")]),
+            info.attributes
+        );
+    }
+
+    #[test]
+    fn test_parse_rustdoc() {
+        let info = parse("rust,ignore");
+        assert_eq!("rust", info.language);
+        assert_eq!(attributes(&[("ignore", "")]), info.attributes);
+
+        let info = parse("should_panic,edition2021");
+        assert_eq!("rust", info.language);
+        assert_eq!(
+            attributes(&[("should_panic", ""), ("edition2021", "")]),
+            info.attributes
+        );
+    }
+
+    #[test]
+    fn test_find_language() {
+        assert_eq!(Some(13), find_language("{code-block} js", "js"));
+        assert_eq!(Some(2), find_language("{.js .jsx}", "js"));
+        assert_eq!(None, find_language("ignore", "rust"));
+    }
+
+    #[test]
+    fn test_count_directive_option_lines() {
+        assert_eq!(
+            3,
+            count_directive_option_lines(":linenos:\n:caption: a b\n\nx = 1\n")
+        );
+        assert_eq!(
+            3,
+            count_directive_option_lines("---\ntags: [x]\n---\nx = 1\n")
+        );
+        assert_eq!(0, count_directive_option_lines("x = {'a': 1}\n"));
+        assert_eq!(0, count_directive_option_lines(":: not an option\n"));
+    }
+}
@@ -0,0 +1,123 @@
+use super::config::Language;
+use textwrap::dedent;
+
+/// Returns the line comment of a language, used for the separators of joined
+/// codeblocks.
+pub fn get_comment<'a>(language: &str, options: &'a Language) -> Option<&'a str> {
+    if let Some(comment) = &options.comment {
+        return Some(comment);
+    }
+    let comment = match language {
+        "python" | "bash" | "sh" | "zsh" | "fish" | "ruby" | "perl" | "r" | "yaml" | "toml"
+        | "elixir" | "julia" | "nix" | "powershell" | "make" | "dockerfile" => "#",
+        "rust" | "javascript" | "typescript" | "js" | "ts" | "jsx" | "tsx" | "go" | "c" | "cpp"
+        | "c++" | "java" | "kotlin" | "swift" | "scala" | "dart" | "csharp" | "cs" | "php"
+        | "zig" | "groovy" | "jsonc" => "//",
+        "lua" | "sql" | "haskell" | "elm" => "--",
+        "lisp" | "clojure" | "scheme" | "fennel" => ";;",
+        _ => return None,
+    };
+    Some(comment)
+}
+
+fn get_separator(comment: &str, marker: &str, index: usize) -> String {
+    format!("{comment} {marker} {index}")
+}
+
+/// Returns a marker for the separators that is in none of the contents, so
+/// a line of a codeblock can not be mistaken for a separator.
+fn get_marker(contents: &[&str]) -> String {
+    std::iter::once(String::from("cbfmt-join"))
+        .chain((1..).map(|n| format!("cbfmt-join-{n}")))
+        .find(|m| !contents.iter().any(|c| c.contains(m.as_str())))
+        .unwrap()
+}
+
+/// Joins the contents of codeblocks into one document, each starts with a
+/// separator comment. Returns the document and the marker of the separators.
+pub fn join(contents: &[&str], comment: &str) -> (String, String) {
+    let marker = get_marker(contents);
+    let mut result = String::new();
+    for (i, content) in contents.iter().enumerate() {
+        result.push_str(&get_separator(comment, &marker, i));
+        result.push('\n');
+        result.push_str(content);
+    }
+    (result, marker)
+}
+
+/// Splits formatted output back into the contents of the codeblocks. Lines the
+/// formatter moved above the first separator, like sorted imports, go to the
+/// first codeblock. Returns None if a separator is missing.
+pub fn split(output: &str, comment: &str, marker: &str, count: usize) -> Option<Vec<String>> {
+    let lines = output.lines().collect::<Vec<_>>();
+    let mut positions = Vec::new();
+    let mut start = 0;
+    for i in 0..count {
+        let separator = get_separator(comment, marker, i);
+        let position = start + lines[start..].iter().position(|l| l.trim() == separator)?;
+        positions.push(position);
+        start = position + 1;
+    }
+
+    let mut result = Vec::new();
+    for i in 0..count {
+        let end = positions.get(i + 1).copied().unwrap_or(lines.len());
+        let mut block: Vec<&str> = Vec::new();
+        if i == 0 {
+            block.extend(&lines[..positions[0]]);
+        }
+        block.extend(&lines[positions[i] + 1..end]);
+
+        let first = block.iter().position(|l| !l.trim().is_empty());
+        let last = block.iter().rposition(|l| !l.trim().is_empty());
+        let text = match (first, last) {
+            (Some(first), Some(last)) => block[first..=last]
+                .iter()
+                .map(|l| format!("{l}\n"))
+                .collect::<String>(),
+            _ => String::new(),
+        };
+        result.push(dedent(&text));
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_split() {
+        let (joined, marker) = join(&["import os\nx=1\n", "def f():\n    pass\n"], "#");
+        assert_eq!(
+            "# cbfmt-join 0\nimport os\nx=1\n# cbfmt-join 1\ndef f():\n    pass\n",
+            joined
+        );
+        assert_eq!("cbfmt-join", marker);
+
+        let output = "import os\n\n# cbfmt-join 0\nx = 1\n\n\n# cbfmt-join 1\ndef f():\n    pass\n";
+        assert_eq!(
+            Some(vec![
+                "import os\n\nx = 1\n".to_string(),
+                "def f():\n    pass\n".to_string()
+            ]),
+            split(output, "#", &marker, 2)
+        );
+        assert_eq!(None, split("# cbfmt-join 0\nx = 1\n", "#", &marker, 2));
+    }
+
+    #[test]
+    fn test_marker_not_in_content() {
+        let contents = ["x = 1\n# cbfmt-join 1\n", "y = 2\n"];
+        let (joined, marker) = join(&contents, "#");
+        assert_eq!("cbfmt-join-1", marker);
+        assert_eq!(
+            Some(vec![
+                "x = 1\n# cbfmt-join 1\n".to_string(),
+                "y = 2\n".to_string()
+            ]),
+            split(&joined, "#", &marker, 2)
+        );
+    }
+}
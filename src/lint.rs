@@ -0,0 +1,110 @@
+use regex::Regex;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// A message of a check command, with the position in the document.
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub filename: Option<String>,
+    /// 1-based line in the document.
+    pub line: usize,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(filename) = &self.filename {
+            write!(formatter, "{filename}:")?;
+        }
+        write!(formatter, "{}:", self.line)?;
+        if let Some(column) = self.column {
+            write!(formatter, "{column}:")?;
+        }
+        write!(formatter, " {}", self.message)
+    }
+}
+
+fn get_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new(r"^(?:[A-Za-z]:)?[^:\s]*:(\d+):(?:(\d+):)?\s*(.+)$").unwrap())
+}
+
+/// Parses `file:line:column: message` and `file:line: message` lines in the
+/// output of a check command. Lines and columns are moved by the row of the
+/// first line of the codeblock content and the indent that was removed from
+/// it. If the command failed without a line in this form, the whole output is
+/// reported at the first line of the codeblock.
+pub fn parse(output: &str, success: bool, start: usize, indent: usize) -> Vec<Diagnostic> {
+    let mut result = output
+        .lines()
+        .filter_map(|l| get_regex().captures(l.trim_end()))
+        .filter_map(|c| {
+            let line = c[1].parse::<usize>().ok()?;
+            Some(Diagnostic {
+                filename: None,
+                line: start + line.max(1),
+                column: c
+                    .get(2)
+                    .and_then(|m| m.as_str().parse::<usize>().ok())
+                    .map(|c| c + indent),
+                message: c[3].to_string(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if result.is_empty() && !success {
+        let message = output.trim();
+        result.push(Diagnostic {
+            filename: None,
+            line: start + 1,
+            column: None,
+            message: if message.is_empty() {
+                String::from("Check failed without output.")
+            } else {
+                message.to_string()
+            },
+        });
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let output =
+            "-:1:6: note: Double quote to prevent globbing. [SC2086]\n\nIn - line 2:\n-:2: error\n";
+        assert_eq!(
+            vec![
+                Diagnostic {
+                    filename: None,
+                    line: 11,
+                    column: Some(8),
+                    message: "note: Double quote to prevent globbing. [SC2086]".to_string(),
+                },
+                Diagnostic {
+                    filename: None,
+                    line: 12,
+                    column: None,
+                    message: "error".to_string(),
+                },
+            ],
+            parse(output, false, 10, 2)
+        );
+        assert_eq!(
+            "a.md:11: SyntaxError: invalid syntax",
+            parse("SyntaxError: invalid syntax\n", false, 10, 0)
+                .into_iter()
+                .map(|d| Diagnostic {
+                    filename: Some("a.md".to_string()),
+                    ..d
+                }
+                .to_string())
+                .collect::<String>()
+        );
+        assert!(parse("All checks passed!\n", true, 10, 0).is_empty());
+    }
+}
@@ -1,9 +1,26 @@
 use clap::{App, Arg, ArgMatches};
+mod batch;
+mod builtin;
 mod config;
+mod daemon;
+mod detect;
+mod fence;
 mod format;
+mod hidden;
 use format::FormatResult;
+mod ignore;
+mod info;
+mod join;
+mod lint;
+mod org;
+#[cfg(feature = "plugins")]
+mod plugin;
+mod position;
+mod protect;
+mod session;
 mod tree;
 mod utils;
+mod wrap;
 use futures::{stream::FuturesUnordered, StreamExt};
 use std::process;
 use termcolor::{ColorChoice, StandardStream};
@@ -54,6 +71,19 @@ async fn main() {
                     .takes_value(false)
                     .help("Ignore formatting errors and continue with the next codeblock.")
             )
+            .arg(
+                Arg::with_name("annotate")
+                    .long("annotate")
+                    .takes_value(false)
+                    .help("Write the detected or default language into the tag of untagged codeblocks.")
+            )
+            .arg(
+                Arg::with_name("lint")
+                    .long("lint")
+                    .takes_value(false)
+                    .conflicts_with("write")
+                    .help("Run the check commands of the languages on the codeblocks and print their diagnostics with the position in the document. Exit with exit code 1 if there are diagnostics.")
+            )
             .arg(
                 Arg::with_name("parser")
                     .short('p')
@@ -114,19 +144,27 @@ async fn main() {
     };
     let conf = match config::get(&config_path) {
         Ok(c) => c,
-        Err(_) => {
-            utils::print_error(&mut stderr, "Could not parse config file.");
+        Err(e) => {
+            utils::print_error(&mut stderr, &format!("Could not parse config file. {e}"));
             process::exit(1);
         }
     };
 
-    match matches.values_of("files") {
+    daemon::init(&conf.daemons);
+    #[cfg(feature = "plugins")]
+    plugin::init(&conf.plugins, &config_path);
+    let success = match matches.values_of("files") {
         Some(_) => use_files(matches, &conf, color_choice).await,
         None => use_stdin(matches, &conf).await,
+    };
+    daemon::stop();
+
+    if !success {
+        process::exit(1);
     }
 }
 
-async fn use_files(matches: ArgMatches, conf: &config::Conf, color_choice: ColorChoice) {
+async fn use_files(matches: ArgMatches, conf: &config::Conf, color_choice: ColorChoice) -> bool {
     let mut stdout = StandardStream::stdout(color_choice);
     let mut stderr = StandardStream::stderr(color_choice);
 
@@ -134,24 +172,36 @@ async fn use_files(matches: ArgMatches, conf: &config::Conf, color_choice: Color
     let write = matches.is_present("write");
     let best_effort = matches.is_present("best_effort");
     let fail_fast = matches.is_present("fail_fast");
+    let annotate = matches.is_present("annotate");
+    let lint = matches.is_present("lint");
     let files = matches.values_of("files").unwrap();
     let parser = matches.value_of("parser");
 
+    let worker = batch::Worker::start();
     let mut futures: FuturesUnordered<_> = FuturesUnordered::new();
     let files = match utils::get_files(files) {
         Ok(f) => f,
         Err(e) => {
             utils::print_error(&mut stderr, &e.to_string());
-            process::exit(1);
+            return false;
         }
     };
+    let options = format::RunOptions {
+        best_effort,
+        annotate,
+        lint,
+        ..Default::default()
+    };
     for filename in files {
-        futures.push(format::run_file(conf, filename, parser, write, best_effort));
+        futures.push(format::run_file(
+            conf, filename, parser, write, options, &worker,
+        ));
     }
 
     let mut error_count = 0;
     let mut unchanged_count = 0;
     let mut changed_count = 0;
+    let mut lint_count = 0;
 
     while let Some(result) = futures.next().await {
         match result {
@@ -160,7 +210,7 @@ async fn use_files(matches: ArgMatches, conf: &config::Conf, color_choice: Color
                 if check {
                     continue;
                 }
-                if write {
+                if write || lint {
                     utils::print_unchanged(&mut stdout, &f);
                 } else {
                     utils::print_ok(&mut stdout, &f);
@@ -180,6 +230,16 @@ async fn use_files(matches: ArgMatches, conf: &config::Conf, color_choice: Color
                     break;
                 }
             }
+            FormatResult::Lint(diagnostics) => {
+                lint_count += 1;
+                for diagnostic in diagnostics {
+                    println!("{diagnostic}");
+                }
+                if fail_fast {
+                    println!("Failed fast...");
+                    break;
+                }
+            }
             FormatResult::Err(e) => {
                 error_count += 1;
                 if check {
@@ -199,27 +259,45 @@ async fn use_files(matches: ArgMatches, conf: &config::Conf, color_choice: Color
         }
     }
 
-    let total_count = unchanged_count + changed_count + error_count;
+    let total_count = unchanged_count + changed_count + error_count + lint_count;
     if write {
         println!("\n[{changed_count}/{total_count}] files were written.");
     }
 
-    if !write && !check {
+    if lint && !check {
+        println!("\n[{unchanged_count}/{total_count}] files have no diagnostics.");
+    } else if !write && !check {
         println!("\n[{unchanged_count}/{total_count}] files are formatted correctly.");
     }
 
-    if error_count > 0 || (changed_count > 0 && !write) {
-        process::exit(1);
-    }
+    error_count == 0 && lint_count == 0 && (changed_count == 0 || write)
 }
 
-async fn use_stdin(matches: ArgMatches, conf: &config::Conf) {
+async fn use_stdin(matches: ArgMatches, conf: &config::Conf) -> bool {
     let parser = matches.value_of("parser");
     let filename = matches.value_of("stdin_filepath");
     let best_effort = matches.is_present("best_effort");
+    let annotate = matches.is_present("annotate");
+    let lint = matches.is_present("lint");
 
-    if let FormatResult::Err(e) = format::run_stdin(conf, filename, parser, best_effort).await {
-        eprintln!("{e}");
-        process::exit(1);
+    let options = format::RunOptions {
+        best_effort,
+        annotate,
+        lint,
+        ..Default::default()
+    };
+    let worker = batch::Worker::start();
+    match format::run_stdin(conf, filename, parser, options, &worker).await {
+        FormatResult::Err(e) => {
+            eprintln!("{e}");
+            false
+        }
+        FormatResult::Lint(diagnostics) => {
+            for diagnostic in diagnostics.iter() {
+                println!("{diagnostic}");
+            }
+            diagnostics.is_empty()
+        }
+        _ => true,
     }
 }
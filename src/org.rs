@@ -0,0 +1,180 @@
+use super::config::OrgConf;
+use super::info::InfoString;
+use super::utils;
+use std::collections::HashMap;
+
+/// Parses the header of an org source block, `python :results output :exports both`.
+/// The first word is the language, header arguments are stored as attributes.
+pub fn parse_header(text: &str) -> InfoString {
+    let mut words = text.split_whitespace();
+    let language = words.next().unwrap_or("").to_string();
+
+    let mut attributes = HashMap::new();
+    let mut key: Option<&str> = None;
+    let mut value: Vec<&str> = Vec::new();
+    for word in words {
+        if let Some(k) = word.strip_prefix(':') {
+            if let Some(prev) = key {
+                attributes.insert(prev.to_string(), unquote(&value.join(" ")));
+            }
+            key = Some(k);
+            value.clear();
+        } else {
+            value.push(word);
+        }
+    }
+    if let Some(prev) = key {
+        attributes.insert(prev.to_string(), unquote(&value.join(" ")));
+    }
+
+    InfoString {
+        language,
+        directive: None,
+        attributes,
+    }
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(v) => v.to_string(),
+        None => value.to_string(),
+    }
+}
+
+/// Returns true if the contents of a source block keep their indentation,
+/// with `preserve_indentation` or the `-i` switch in the header.
+pub fn preserves_indentation(conf: &OrgConf, header: &str) -> bool {
+    // Switches come after the language and before the header arguments
+    conf.preserve_indentation
+        || header
+            .split_whitespace()
+            .skip(2)
+            .take_while(|w| !w.starts_with(':'))
+            .any(|w| w == "-i")
+}
+
+/// Returns the indent for the contents of a source block.
+pub fn get_indent(conf: &OrgConf, header: &str, content: &str) -> String {
+    if preserves_indentation(conf, header) {
+        return String::new();
+    }
+    match conf.content_indentation {
+        Some(n) => utils::get_start_whitespace(header) + &" ".repeat(n),
+        None => utils::get_common_indent(content),
+    }
+}
+
+/// Removes the comma org adds in front of lines starting with `*` or `#+`
+/// inside source blocks.
+pub fn unescape(text: &str) -> String {
+    map_lines(text, |line| {
+        let indent = line.len() - line.trim_start_matches([' ', '\t']).len();
+        let rest = &line[indent..];
+        let commas = rest.len() - rest.trim_start_matches(',').len();
+        if commas > 0 && is_escapable(&rest[commas..]) {
+            format!("{}{}", &line[..indent], &rest[1..])
+        } else {
+            line.to_string()
+        }
+    })
+}
+
+/// Adds a comma in front of lines that org would otherwise read as
+/// headlines or keywords.
+pub fn escape(text: &str) -> String {
+    map_lines(text, |line| {
+        let indent = line.len() - line.trim_start_matches([' ', '\t']).len();
+        let rest = &line[indent..];
+        if is_escapable(rest.trim_start_matches(',')) {
+            format!("{},{}", &line[..indent], rest)
+        } else {
+            line.to_string()
+        }
+    })
+}
+
+fn is_escapable(text: &str) -> bool {
+    text.starts_with('*') || text.starts_with("#+")
+}
+
+fn map_lines(text: &str, f: impl Fn(&str) -> String) -> String {
+    let mut result = String::new();
+    for line in text.split_inclusive('\n') {
+        match line.strip_suffix('\n') {
+            Some(l) => {
+                result.push_str(&f(l));
+                result.push('\n');
+            }
+            None => result.push_str(&f(line)),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        let info = parse_header("python :results output :exports both");
+        assert_eq!("python", info.language);
+        assert_eq!(Some(&"output".to_string()), info.attributes.get("results"));
+        assert_eq!(Some(&"both".to_string()), info.attributes.get("exports"));
+
+        let info = parse_header("python :cbfmt \"ruff format -\"");
+        assert_eq!(
+            Some(&"ruff format -".to_string()),
+            info.attributes.get("cbfmt")
+        );
+    }
+
+    #[test]
+    fn test_get_indent() {
+        let conf = OrgConf::default();
+        let content = "    x\n\n      y\n";
+        assert_eq!("    ", get_indent(&conf, "#+begin_src python", content));
+
+        let conf = OrgConf {
+            content_indentation: Some(2),
+            ..Default::default()
+        };
+        assert_eq!("  ", get_indent(&conf, "#+begin_src python", content));
+        assert_eq!("    ", get_indent(&conf, "  #+begin_src python", content));
+
+        let conf = OrgConf {
+            preserve_indentation: true,
+            content_indentation: Some(2),
+        };
+        assert_eq!("", get_indent(&conf, "  #+begin_src python", content));
+    }
+
+    #[test]
+    fn test_preserve_indentation_switch() {
+        let conf = OrgConf {
+            content_indentation: Some(2),
+            ..Default::default()
+        };
+        assert!(preserves_indentation(
+            &conf,
+            "#+begin_src python -n -i :results output"
+        ));
+        assert_eq!("", get_indent(&conf, "#+begin_src python -i", "    x\n"));
+        assert!(!preserves_indentation(
+            &conf,
+            "#+begin_src python :var x=-i"
+        ));
+        assert!(!preserves_indentation(
+            &OrgConf::default(),
+            "#+begin_src python"
+        ));
+    }
+
+    #[test]
+    fn test_escape_roundtrip() {
+        let escaped = ",* heading\n  ,#+keyword\n,,* escaped comma\n, normal\n#not keyword\n";
+        let unescaped = "* heading\n  #+keyword\n,* escaped comma\n, normal\n#not keyword\n";
+        assert_eq!(unescaped, unescape(escaped));
+        assert_eq!(escaped, escape(unescaped));
+    }
+}
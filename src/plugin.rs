@@ -0,0 +1,213 @@
+use super::config::Plugin;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use wasmtime::{Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, Trap};
+use wasmtime_wasi::pipe::{MemoryInputPipe, MemoryOutputPipe};
+use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+use wasmtime_wasi::{I32Exit, WasiCtxBuilder};
+
+/// Formatter commands with this prefix run a WebAssembly plugin.
+pub const PREFIX: &str = "plugin:";
+
+/// Maximum size of the output of a plugin.
+const OUTPUT_LIMIT: usize = 64 * 1024 * 1024;
+
+static PLUGINS: OnceLock<HashMap<String, Plugin>> = OnceLock::new();
+static ENGINE: OnceLock<Engine> = OnceLock::new();
+static MODULES: Mutex<Vec<(String, Module)>> = Mutex::new(Vec::new());
+
+struct State {
+    wasi: WasiP1Ctx,
+    limits: StoreLimits,
+}
+
+/// Sets the plugins from the config. Relative paths are relative to the
+/// directory of the config file.
+pub fn init(plugins: &HashMap<String, Plugin>, config_path: &str) {
+    let dir = Path::new(config_path).parent().unwrap_or(Path::new(""));
+    let plugins = plugins
+        .iter()
+        .map(|(name, plugin)| {
+            let path = dir.join(&plugin.path).to_string_lossy().to_string();
+            (
+                name.to_owned(),
+                Plugin {
+                    path,
+                    ..plugin.clone()
+                },
+            )
+        })
+        .collect();
+    let _ = PLUGINS.set(plugins);
+}
+
+/// Formats the content with the plugin. The plugin is a WASI command that
+/// reads the content from stdin and writes the result to stdout. It has no
+/// access to files, the environment, or the network, and its work and memory
+/// are limited.
+pub fn format(name: &str, content: &str) -> Result<String, String> {
+    let plugin = PLUGINS
+        .get()
+        .and_then(|p| p.get(name))
+        .ok_or_else(|| format!("Plugin {name} is not configured."))?;
+    let engine = get_engine()?;
+    let module = get_module(engine, name, plugin)?;
+
+    let mut linker: Linker<State> = Linker::new(engine);
+    preview1::add_to_linker_sync(&mut linker, |s: &mut State| &mut s.wasi)
+        .map_err(|e| e.to_string())?;
+
+    let stdout = MemoryOutputPipe::new(OUTPUT_LIMIT);
+    let stderr = MemoryOutputPipe::new(OUTPUT_LIMIT);
+    let mut args = vec![name.to_string()];
+    args.extend(plugin.args.iter().cloned());
+    let wasi = WasiCtxBuilder::new()
+        .stdin(MemoryInputPipe::new(content.to_string()))
+        .stdout(stdout.clone())
+        .stderr(stderr.clone())
+        .args(&args)
+        .build_p1();
+    let limits = StoreLimitsBuilder::new()
+        .memory_size(plugin.memory * 1024 * 1024)
+        .instances(1)
+        .build();
+    let mut store = Store::new(engine, State { wasi, limits });
+    store.limiter(|s| &mut s.limits);
+    store.set_fuel(plugin.fuel).map_err(|e| e.to_string())?;
+
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .map_err(|e| e.to_string())?;
+    let start = instance
+        .get_typed_func::<(), ()>(&mut store, "_start")
+        .map_err(|e| e.to_string())?;
+    let status = match start.call(&mut store, ()) {
+        Ok(()) => 0,
+        Err(e) => match (e.downcast_ref::<I32Exit>(), e.downcast_ref::<Trap>()) {
+            (Some(exit), _) => exit.0,
+            (_, Some(Trap::OutOfFuel)) => return Err(format!("Plugin {name} ran out of fuel.")),
+            _ => return Err(e.to_string()),
+        },
+    };
+
+    if status == 0 {
+        Ok(String::from_utf8_lossy(&stdout.contents()).to_string())
+    } else {
+        Err(String::from_utf8_lossy(&stderr.contents()).to_string())
+    }
+}
+
+fn get_engine() -> Result<&'static Engine, String> {
+    if let Some(engine) = ENGINE.get() {
+        return Ok(engine);
+    }
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config).map_err(|e| e.to_string())?;
+    Ok(ENGINE.get_or_init(|| engine))
+}
+
+/// Returns the compiled module of the plugin. Each plugin is compiled once.
+fn get_module(engine: &Engine, name: &str, plugin: &Plugin) -> Result<Module, String> {
+    let mut modules = MODULES.lock().map_err(|e| e.to_string())?;
+    if let Some((_, module)) = modules.iter().find(|(n, _)| n == name) {
+        return Ok(module.clone());
+    }
+
+    let module =
+        Module::from_file(engine, &plugin.path).map_err(|e| format!("{}: {e}", plugin.path))?;
+    modules.push((name.to_string(), module.clone()));
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPPER: &str = r#"
+        (module
+            (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "_start")
+                (local $len i32) (local $i i32) (local $c i32)
+                (i32.store (i32.const 0) (i32.const 1024))
+                (i32.store (i32.const 4) (i32.const 60000))
+                (drop (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+                (local.set $len (i32.load (i32.const 8)))
+                (block $end
+                    (loop $next
+                        (br_if $end (i32.ge_u (local.get $i) (local.get $len)))
+                        (local.set $c (i32.load8_u (i32.add (i32.const 1024) (local.get $i))))
+                        (if (i32.and (i32.ge_u (local.get $c) (i32.const 97)) (i32.le_u (local.get $c) (i32.const 122)))
+                            (then (i32.store8 (i32.add (i32.const 1024) (local.get $i)) (i32.sub (local.get $c) (i32.const 32)))))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br $next)))
+                (i32.store (i32.const 4) (local.get $len))
+                (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))
+    "#;
+
+    const FAIL: &str = r#"
+        (module
+            (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 16) "invalid input")
+            (func (export "_start")
+                (i32.store (i32.const 0) (i32.const 16))
+                (i32.store (i32.const 4) (i32.const 13))
+                (drop (call $fd_write (i32.const 2) (i32.const 0) (i32.const 1) (i32.const 8)))
+                (call $proc_exit (i32.const 1))))
+    "#;
+
+    const LOOP: &str = r#"(module (func (export "_start") (loop $l (br $l))))"#;
+
+    const BIG: &str = r#"(module (memory 32) (func (export "_start")))"#;
+
+    fn init_plugins() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("cbfmt-plugins-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let plugins = [
+            ("upper", UPPER),
+            ("fail", FAIL),
+            ("loop", LOOP),
+            ("big", BIG),
+        ]
+        .iter()
+        .map(|(name, wat)| {
+            let path = dir.join(format!("{name}.wasm"));
+            std::fs::write(&path, wat::parse_str(wat).unwrap()).unwrap();
+            let plugin = Plugin {
+                path: path.to_string_lossy().to_string(),
+                args: Vec::new(),
+                fuel: 1_000_000,
+                memory: 1,
+            };
+            (name.to_string(), plugin)
+        })
+        .collect();
+        init(&plugins, "");
+        dir
+    }
+
+    #[test]
+    fn test_format() {
+        let dir = init_plugins();
+        assert_eq!(
+            Ok("SELECT 1;\n".to_string()),
+            format("upper", "select 1;\n")
+        );
+        assert_eq!(Err("invalid input".to_string()), format("fail", "x"));
+        assert_eq!(
+            Err("Plugin loop ran out of fuel.".to_string()),
+            format("loop", "x")
+        );
+        assert!(format("big", "x").is_err());
+        assert_eq!(
+            Err("Plugin missing is not configured.".to_string()),
+            format("missing", "x")
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
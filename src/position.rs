@@ -0,0 +1,128 @@
+use regex::{Captures, Regex};
+use std::sync::OnceLock;
+
+/// Document rows of the lines of formatter input. Lines that are not in the
+/// document, like wrappers and separators, have no row.
+pub type Rows = Vec<Option<usize>>;
+
+/// Returns the rows of `count` lines starting at `start`.
+pub fn get_rows(start: usize, count: usize) -> Rows {
+    (start..start + count).map(Some).collect()
+}
+
+fn get_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| {
+        Regex::new(
+            r"(?im)(?P<word>\bline )(?P<line>\d+)|(?P<before>^|[\s:(\[])(?P<pair>\d+):(?P<column>\d+)\b|(?P<colon>:)(?P<single>\d+)(?P<after>:(?:\s|$))",
+        )
+        .unwrap()
+    })
+}
+
+/// Rewrites the line numbers in formatter messages, like `line 3`, `3:5`, and
+/// `-:3:`, to 1-based lines in the document. Columns are moved by the indent
+/// that was removed from the codeblock. Returns the message and the row of
+/// the first line that was rewritten.
+pub fn map_lines(msg: &str, rows: &[Option<usize>], indent: usize) -> (String, Option<usize>) {
+    let mut first = None;
+    let get_row = |line: &str| {
+        let line = line.parse::<usize>().ok()?;
+        rows.get(line.checked_sub(1)?).copied().flatten()
+    };
+
+    let result = get_regex().replace_all(msg, |c: &Captures| {
+        let (prefix, line, suffix) = if let Some(line) = c.name("line") {
+            (&c["word"], line.as_str(), String::new())
+        } else if let Some(line) = c.name("pair") {
+            let column = c["column"].parse::<usize>().unwrap_or_default();
+            let column = match get_row(line.as_str()) {
+                Some(_) => column + indent,
+                None => column,
+            };
+            (&c["before"], line.as_str(), format!(":{column}"))
+        } else {
+            (&c["colon"], &c["single"], c["after"].to_string())
+        };
+
+        match get_row(line) {
+            Some(row) => {
+                first = first.or(Some(row));
+                format!("{prefix}{}{suffix}", row + 1)
+            }
+            None => format!("{prefix}{line}{suffix}"),
+        }
+    });
+    (result.to_string(), first)
+}
+
+/// Returns the lines of the content around the row, or the first lines
+/// without a row, with their line numbers in the document.
+pub fn get_excerpt(content: &str, rows: &[Option<usize>], row: Option<usize>) -> String {
+    let lines = content
+        .lines()
+        .zip(rows)
+        .filter_map(|(line, row)| row.map(|r| (r, line)))
+        .collect::<Vec<_>>();
+    let lines = match row {
+        Some(row) => lines
+            .iter()
+            .filter(|(r, _)| *r + 1 >= row && *r <= row + 1)
+            .collect::<Vec<_>>(),
+        None => lines.iter().take(3).collect(),
+    };
+    let width = lines.last().map_or(0, |(r, _)| (r + 1).to_string().len());
+    lines
+        .iter()
+        .map(|(r, line)| format!("{:>width$} | {line}\n", r + 1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_lines() {
+        let mut rows = vec![None];
+        rows.extend(get_rows(10, 3));
+        assert_eq!(
+            (
+                "error: cannot format -: Cannot parse: 12:6: def f(:".to_string(),
+                Some(11)
+            ),
+            map_lines(
+                "error: cannot format -: Cannot parse: 3:4: def f(:",
+                &rows,
+                2
+            )
+        );
+        assert_eq!(
+            (
+                "File \"<stdin>\", line 11\n-:13: error\n<stdin>:1:1 in wrapper".to_string(),
+                Some(10)
+            ),
+            map_lines(
+                "File \"<stdin>\", line 2\n-:4: error\n<stdin>:1:1 in wrapper",
+                &rows,
+                0
+            )
+        );
+    }
+
+    #[test]
+    fn test_get_excerpt() {
+        let content = "fn main() {\na\nb\nc\nd\ne\n}\n";
+        let mut rows = vec![None];
+        rows.extend(get_rows(98, 5));
+        rows.push(None);
+        assert_eq!(
+            " 99 | a\n100 | b\n101 | c\n",
+            get_excerpt(content, &rows, None)
+        );
+        assert_eq!(
+            "100 | b\n101 | c\n102 | d\n",
+            get_excerpt(content, &rows, Some(100))
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use regex::Regex;
+
+/// Joins the protect patterns of a language into one regex, so matches of
+/// different patterns can not overlap.
+pub fn get_regex(patterns: &[String]) -> Result<Option<Regex>, regex::Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let pattern = patterns
+        .iter()
+        .map(|p| format!("(?:{p})"))
+        .collect::<Vec<_>>()
+        .join("|");
+    Regex::new(&pattern).map(Some)
+}
+
+/// Replaces the matches of the regex with identifiers formatters accept, like
+/// `_cbfmt0_`. Placeholders are padded to the length of the match so lines
+/// wrap the same. Returns the content and the placeholders with their matches.
+pub fn protect(content: &str, regex: &Regex) -> (String, Vec<(String, String)>) {
+    // The prefix is not in the content, text that looks like a placeholder stays as it is
+    let prefix = (0..)
+        .map(|n| format!("_cbfmt{}", "x".repeat(n)))
+        .find(|p| !content.contains(p.as_str()))
+        .unwrap();
+    let mut placeholders = Vec::new();
+    let result = regex.replace_all(content, |captures: &regex::Captures| {
+        let text = captures[0].to_string();
+        let mut placeholder = format!("{prefix}{}_", placeholders.len());
+        while placeholder.len() < text.chars().count() {
+            placeholder.push('_');
+        }
+        placeholders.push((placeholder.to_owned(), text));
+        placeholder
+    });
+    (result.into_owned(), placeholders)
+}
+
+/// Puts the matches back in place of the placeholders. Returns the match of
+/// the first placeholder that is missing or duplicated in the output.
+pub fn restore(output: &str, placeholders: &[(String, String)]) -> Result<String, String> {
+    let mut result = output.to_string();
+    for (placeholder, text) in placeholders {
+        if result.matches(placeholder.as_str()).count() != 1 {
+            return Err(text.to_owned());
+        }
+        result = result.replacen(placeholder.as_str(), text, 1);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protect_roundtrip() {
+        let regex = get_regex(&[r"\{\{.*?\}\}".to_string(), r"\.\.\.".to_string()])
+            .unwrap()
+            .unwrap();
+        let (protected, placeholders) = protect("x = {{ value }}\n...\n", &regex);
+        assert_eq!("x = _cbfmt0____\n_cbfmt1_\n", protected);
+        assert_eq!(
+            Ok("x  =  {{ value }}\n...\n".to_string()),
+            restore("x  =  _cbfmt0____\n_cbfmt1_\n", &placeholders)
+        );
+        assert_eq!(
+            Err("...".to_string()),
+            restore("x = _cbfmt0____\n", &placeholders)
+        );
+    }
+
+    #[test]
+    fn test_placeholder_in_content() {
+        let regex = get_regex(&[r"\.\.\.".to_string()]).unwrap().unwrap();
+        let (protected, placeholders) = protect("_cbfmt0_ = ...\n", &regex);
+        assert_eq!("_cbfmt0_ = _cbfmtx0_\n", protected);
+        assert_eq!(
+            Ok("_cbfmt0_ = ...\n".to_string()),
+            restore(&protected, &placeholders)
+        );
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(get_regex(&["(".to_string()]).is_err());
+        assert!(get_regex(&[]).unwrap().is_none());
+    }
+}
@@ -0,0 +1,173 @@
+use super::config::Session;
+use regex::Regex;
+use std::future::Future;
+
+/// Returns the built-in session for a language.
+pub fn get_builtin(name: &str) -> Option<Session> {
+    let (prompt, continuation, continuation_text, language) = match name {
+        "pycon" => (r">>>( |$)", r"\.\.\.( |$)", "... ", "python"),
+        "console" | "shell-session" | "shellsession" => (r"\$( |$)", r">( |$)", "> ", "bash"),
+        "irb" => (
+            r"(irb\([^)]*\):\d+:\d+>|>>)( |$)",
+            r#"(irb\([^)]*\):\d+:\d+[*"']|\?>)( |$)"#,
+            "?> ",
+            "ruby",
+        ),
+        _ => return None,
+    };
+    Some(Session {
+        prompt: prompt.to_string(),
+        continuation: Some(continuation.to_string()),
+        continuation_text: Some(continuation_text.to_string()),
+        language: language.to_string(),
+    })
+}
+
+enum Segment<'a> {
+    Output(&'a str),
+    Input {
+        row: usize,
+        prompts: Vec<&'a str>,
+        code: Vec<&'a str>,
+    },
+}
+
+/// Formats the input of a session, the lines after the prompt and the
+/// continuation lines that follow them. Each input is formatted on its own,
+/// prompts and output are kept as they are. `format` gets the input and the
+/// row of its first line in the content.
+pub async fn format<E, F>(
+    session: &Session,
+    content: &str,
+    format: impl Fn(String, usize) -> F,
+    error: impl Fn(String) -> E,
+) -> Result<String, E>
+where
+    F: Future<Output = Result<String, E>>,
+{
+    let prompt = compile(&session.prompt).map_err(|e| error(e.to_string()))?;
+    let continuation = match &session.continuation {
+        Some(c) => Some(compile(c).map_err(|e| error(e.to_string()))?),
+        None => None,
+    };
+
+    let mut segments = Vec::new();
+    for (row, line) in content.lines().enumerate() {
+        if let Some(m) = prompt.find(line) {
+            segments.push(Segment::Input {
+                row,
+                prompts: vec![m.as_str()],
+                code: vec![&line[m.end()..]],
+            });
+            continue;
+        }
+        if let (Some(Segment::Input { prompts, code, .. }), Some(c)) =
+            (segments.last_mut(), &continuation)
+        {
+            if let Some(m) = c.find(line) {
+                prompts.push(m.as_str());
+                code.push(&line[m.end()..]);
+                continue;
+            }
+        }
+        segments.push(Segment::Output(line));
+    }
+
+    let mut result = String::new();
+    for segment in segments {
+        let (row, prompts, code) = match segment {
+            Segment::Output(line) => {
+                result.push_str(line);
+                result.push('\n');
+                continue;
+            }
+            Segment::Input { row, prompts, code } => (row, prompts, code),
+        };
+
+        // Empty continuation lines end a block, formatters remove them
+        let trailing = code
+            .iter()
+            .rev()
+            .take_while(|l| l.trim().is_empty())
+            .count();
+        let input = code[..code.len() - trailing].join("\n") + "\n";
+        let output = if input.trim().is_empty() {
+            String::new()
+        } else {
+            format(input, row).await?
+        };
+
+        let mut lines = output.lines().collect::<Vec<_>>();
+        lines.extend(std::iter::repeat_n("", trailing));
+        if lines.is_empty() {
+            lines.push("");
+        }
+        // Prompts of empty lines have no trailing space, they are not used for lines with code
+        let has_space = |p: &&&str| p.ends_with(char::is_whitespace);
+        let continuation = prompts[1..]
+            .iter()
+            .rev()
+            .find(has_space)
+            .map(|p| p.to_string())
+            .or_else(|| session.continuation_text.to_owned())
+            .unwrap_or_else(|| prompts[0].to_string());
+        for (i, line) in lines.iter().enumerate() {
+            let prompt = match prompts.get(i) {
+                Some(p) if i == 0 || has_space(&p) => p.to_string(),
+                _ => continuation.to_owned(),
+            };
+            if line.is_empty() {
+                result.push_str(prompt.trim_end());
+            } else {
+                result.push_str(&prompt);
+                result.push_str(line);
+            }
+            result.push('\n');
+        }
+    }
+    Ok(result)
+}
+
+/// Returns true if the first line of the content starts with the prompt.
+pub fn starts_with_prompt(session: &Session, content: &str) -> bool {
+    let first = content.lines().find(|l| !l.trim().is_empty());
+    match (compile(&session.prompt), first) {
+        (Ok(prompt), Some(line)) => prompt.is_match(line),
+        _ => false,
+    }
+}
+
+fn compile(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{pattern})"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn fake_format(text: String, _row: usize) -> Result<String, String> {
+        Ok(text.replace("x=1", "x = 1").replace("if x:", "if x:\n"))
+    }
+
+    #[tokio::test]
+    async fn test_format_pycon() {
+        let session = get_builtin("pycon").unwrap();
+        let content = ">>> x=1\n>>> if x:\n...     print(x)\n...\n1\n>>>\n";
+        assert_eq!(
+            Ok(">>> x = 1\n>>> if x:\n...\n...     print(x)\n...\n1\n>>>\n".to_string()),
+            format(&session, content, fake_format, |e| e).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_format_irb() {
+        let session = get_builtin("irb").unwrap();
+        let content = "irb(main):001:0> x=1\n=> 1\n";
+        assert_eq!(
+            Ok("irb(main):001:0> x = 1\n=> 1\n".to_string()),
+            format(&session, content, fake_format, |e| e).await
+        );
+        assert!(starts_with_prompt(&session, "\n>> x\n"));
+        assert!(!starts_with_prompt(&session, "x\n"));
+    }
+}
@@ -34,7 +34,7 @@ pub fn get_query(parser_lang: &str) -> Option<tree_sitter::Query> {
                 tree_sitter_md::language(),
                 r#"
                     (fenced_code_block
-                        (info_string (language) @language)
+                        (info_string)? @info_string
                         (code_fence_content) @content) @codeblock
                 "#,
             )
@@ -45,10 +45,10 @@ pub fn get_query(parser_lang: &str) -> Option<tree_sitter::Query> {
                 tree_sitter_org::language(),
                 r#"
                     (block
-                        name: (expr) @_name
-                        (#match? @_name "(SRC|src)")
-                        parameter: (expr) @language
-                        contents: (contents) @content) @codeblock
+                        name: (expr) @name
+                        (#match? @name "(SRC|src)")
+                        parameter: (expr)? @header
+                        (contents) @content) @codeblock
                 "#,
             )
             .expect("Could not load org query"),
@@ -58,13 +58,13 @@ pub fn get_query(parser_lang: &str) -> Option<tree_sitter::Query> {
                 tree_sitter_rst::language(),
                 r#"
                     (directive
-                        name: (type) @_name
-                        (#match? @_name "code")
+                        name: (type) @directive
+                        (#match? @directive "^(code|code-block|sourcecode|ipython|doctest)$")
                         body: (body
-                            (arguments) @language
-                            (content) @content
-                            (#offset! @content 0 0 1 0))) @codeblock
-                    
+                            (arguments)? @language
+                            (content) @content)) @codeblock
+
+                    (literal_block) @content @codeblock
                 "#,
             )
             .expect("Could not load restructuredtext query"),
@@ -73,6 +73,15 @@ pub fn get_query(parser_lang: &str) -> Option<tree_sitter::Query> {
     }
 }
 
+pub fn get_parser_lang_from_language(language: &str) -> Option<&str> {
+    match language {
+        "markdown" | "md" => Some("markdown"),
+        "org" => Some("org"),
+        "restructuredtext" | "rst" => Some("restructuredtext"),
+        _ => None,
+    }
+}
+
 pub fn get_parser_lang_from_filename(filename: &str) -> Option<&str> {
     let filename = filename.to_lowercase();
     if filename.ends_with(".md") {
@@ -87,38 +96,114 @@ pub fn get_parser_lang_from_filename(filename: &str) -> Option<&str> {
     None
 }
 
-pub fn handle_directive(
-    directive: &str,
-    range: &tree_sitter::Range,
-    args: &Vec<tree_sitter::QueryPredicateArg>,
-) -> Option<tree_sitter::Range> {
-    match directive {
-        "offset!" => {
-            let start_row_offset = match &args[1] {
-                tree_sitter::QueryPredicateArg::String(value) => value.parse::<usize>().unwrap(),
-                _ => panic!("Unexpected argument type for offset!"),
-            };
-            let start_col_offset = match &args[2] {
-                tree_sitter::QueryPredicateArg::String(value) => value.parse::<usize>().unwrap(),
-                _ => panic!("Unexpected argument type for offset!"),
-            };
-            let end_row_offset = match &args[3] {
-                tree_sitter::QueryPredicateArg::String(value) => value.parse::<usize>().unwrap(),
-                _ => panic!("Unexpected argument type for offset!"),
-            };
-            let end_col_offset = match &args[4] {
-                tree_sitter::QueryPredicateArg::String(value) => value.parse::<usize>().unwrap(),
-                _ => panic!("Unexpected argument type for offset!"),
-            };
-
-            let mut new_range = range.clone();
-            new_range.start_point.row = range.start_point.row + start_row_offset;
-            new_range.start_point.column = range.start_point.column + start_col_offset;
-            new_range.end_point.row = range.end_point.row + end_row_offset;
-            new_range.end_point.column = range.end_point.column + end_col_offset;
-            return Some(new_range);
+pub enum MarkdownCodeblock {
+    Fenced {
+        opening: usize,
+        closing: Option<usize>,
+        column: usize,
+    },
+    Indented {
+        start: usize,
+        end: usize,
+    },
+}
+
+/// Returns all fenced and indented codeblocks in a markdown document.
+pub fn get_markdown_codeblocks(tree: &tree_sitter::Tree) -> Vec<MarkdownCodeblock> {
+    let query = tree_sitter::Query::new(
+        tree_sitter_md::language(),
+        r#"
+            (fenced_code_block) @fenced
+            (indented_code_block) @indented
+        "#,
+    )
+    .expect("Could not load markdown codeblock query");
+
+    let mut result = Vec::new();
+    let mut cursor = tree_sitter::QueryCursor::new();
+    for each_match in cursor.matches(&query, tree.root_node(), &[] as &[u8]) {
+        for capture in each_match.captures.iter() {
+            let node = capture.node;
+            if query.capture_names()[capture.index as usize] == "fenced" {
+                let mut walk = node.walk();
+                let closing = node
+                    .children(&mut walk)
+                    .filter(|c| c.kind() == "fenced_code_block_delimiter")
+                    .nth(1)
+                    .map(|c| c.start_position().row);
+                result.push(MarkdownCodeblock::Fenced {
+                    opening: node.start_position().row,
+                    closing,
+                    column: node.start_position().column,
+                });
+            } else {
+                let end = node.end_position();
+                result.push(MarkdownCodeblock::Indented {
+                    start: node.start_position().row,
+                    end: if end.column > 0 { end.row + 1 } else { end.row },
+                });
+            }
         }
-        &_ => {}
     }
-    None
+
+    result
+}
+
+/// Returns the row and language of every `.. highlight::` directive in a
+/// restructuredtext document.
+pub fn get_highlights(tree: &tree_sitter::Tree, src: &[u8]) -> Vec<(usize, String)> {
+    let query = tree_sitter::Query::new(
+        tree_sitter_rst::language(),
+        r#"
+            (directive
+                name: (type) @_name
+                (#eq? @_name "highlight")
+                body: (body (arguments) @language))
+        "#,
+    )
+    .expect("Could not load restructuredtext highlight query");
+
+    let mut result = Vec::new();
+    let mut cursor = tree_sitter::QueryCursor::new();
+    for each_match in cursor.matches(&query, tree.root_node(), src) {
+        for capture in each_match.captures.iter() {
+            if query.capture_names()[capture.index as usize] == "language" {
+                let language = capture.node.utf8_text(src).unwrap_or("");
+                result.push((
+                    capture.node.start_position().row,
+                    language.split_whitespace().next().unwrap_or("").to_string(),
+                ));
+            }
+        }
+    }
+
+    result
+}
+
+/// Returns the rows of the content of every codeblock in the document, the
+/// end row is exclusive.
+pub fn get_content_rows(
+    parser_lang: &str,
+    tree: &tree_sitter::Tree,
+    src: &[u8],
+) -> Vec<std::ops::Range<usize>> {
+    let query = match get_query(parser_lang) {
+        Some(q) => q,
+        None => return Vec::new(),
+    };
+
+    let mut result = Vec::new();
+    let mut cursor = tree_sitter::QueryCursor::new();
+    for each_match in cursor.matches(&query, tree.root_node(), src) {
+        for capture in each_match.captures.iter() {
+            if query.capture_names()[capture.index as usize] == "content" {
+                let start = capture.node.start_position();
+                let end = capture.node.end_position();
+                let end = if end.column > 0 { end.row + 1 } else { end.row };
+                result.push(start.row..end);
+            }
+        }
+    }
+
+    result
 }
@@ -2,11 +2,8 @@ use super::format::FormatError;
 use super::tree;
 use clap::Values;
 use ignore::WalkBuilder;
-use std::collections::hash_map::DefaultHasher;
 use std::env;
 use std::fs;
-use std::hash::Hash;
-use std::hash::Hasher;
 use std::io;
 use termcolor::{Color, ColorSpec, StandardStream, WriteColor};
 
@@ -24,10 +21,82 @@ pub fn get_start_whitespace(text: &str) -> String {
     result
 }
 
-pub fn get_hash(text: &str) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    text.hash(&mut hasher);
-    hasher.finish()
+pub fn get_common_indent(text: &str) -> String {
+    let mut result: Option<&str> = None;
+
+    for line in text.lines().filter(|l| !l.trim().is_empty()) {
+        let indent = &line[..line.len() - line.trim_start().len()];
+        result = match result {
+            Some(r) => {
+                let len = r
+                    .chars()
+                    .zip(indent.chars())
+                    .take_while(|(a, b)| a == b)
+                    .map(|(a, _)| a.len_utf8())
+                    .sum();
+                Some(&r[..len])
+            }
+            None => Some(indent),
+        };
+    }
+
+    result.unwrap_or("").to_string()
+}
+
+/// Returns the container prefix (blockquote markers, list indent) for the
+/// lines of a codeblock, from the text in front of its opening fence.
+/// List markers are replaced with spaces.
+pub fn get_container_prefix(text: &str) -> String {
+    text.chars()
+        .map(|ch| {
+            if ch == '>' || ch.is_whitespace() {
+                ch
+            } else {
+                ' '
+            }
+        })
+        .collect()
+}
+
+/// Removes the container prefix from a line. Whitespace in the prefix is
+/// optional, so lazy and blank lines are handled as well.
+pub fn strip_container_prefix<'a>(line: &'a str, prefix: &str) -> &'a str {
+    let mut rest = line;
+
+    for ch in prefix.chars() {
+        let next = match rest.chars().next() {
+            Some(n) => n,
+            None => break,
+        };
+        if ch == '>' {
+            if next != '>' {
+                break;
+            }
+        } else if !next.is_whitespace() {
+            continue;
+        }
+        rest = &rest[next.len_utf8()..];
+    }
+
+    rest
+}
+
+/// Adds the prefix to every line of the text. Blank lines get the prefix
+/// without trailing whitespace.
+pub fn add_prefix(text: &str, prefix: &str) -> String {
+    let mut result = String::new();
+
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            result.push_str(prefix.trim_end());
+        } else {
+            result.push_str(prefix);
+            result.push_str(line);
+        }
+        result.push('\n');
+    }
+
+    result
 }
 
 pub fn get_files(files: Values) -> Result<Vec<String>, io::Error> {
@@ -149,3 +218,29 @@ pub fn print_error(stderr: &mut StandardStream, text: &str) {
     stderr.set_color(&color_spec).unwrap();
     eprintln!("]: {text}");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_container_prefix() {
+        assert_eq!(">   ", get_container_prefix("> - "));
+        assert_eq!("    ", get_container_prefix("10. "));
+        assert_eq!("\t", get_container_prefix("\t"));
+    }
+
+    #[test]
+    fn test_strip_container_prefix() {
+        assert_eq!("x = 1", strip_container_prefix(">   x = 1", ">   "));
+        assert_eq!("", strip_container_prefix(">", ">   "));
+        assert_eq!("    x = 1", strip_container_prefix(">     x = 1", "> "));
+        assert_eq!("x = 1", strip_container_prefix("x = 1", "  "));
+    }
+
+    #[test]
+    fn test_add_prefix() {
+        assert_eq!("> a\n>\n> b\n", add_prefix("a\n\nb\n", "> "));
+        assert_eq!("  a\n\n  b\n", add_prefix("a\n\nb\n", "  "));
+    }
+}
@@ -0,0 +1,95 @@
+use super::config::Wrapper;
+use textwrap::dedent;
+
+/// Puts the prefix and suffix of the wrapper around the content.
+pub fn wrap(wrapper: &Wrapper, content: &str) -> String {
+    let mut result = wrapper.prefix.to_owned();
+    if !result.is_empty() && !result.ends_with('\n') {
+        result.push('\n');
+    }
+    result.push_str(content);
+    result.push_str(&wrapper.suffix);
+    if !wrapper.suffix.is_empty() && !wrapper.suffix.ends_with('\n') {
+        result.push('\n');
+    }
+    result
+}
+
+/// Removes the prefix and suffix of the wrapper from formatted output, and the
+/// indentation the formatter added inside of it. Returns None if the output
+/// does not start with the prefix and end with the suffix.
+pub fn unwrap(wrapper: &Wrapper, output: &str) -> Option<String> {
+    let lines = output.lines().collect::<Vec<_>>();
+    let start = match_lines(lines.iter().copied(), wrapper.prefix.lines())?;
+    let end = lines.len() - match_lines(lines.iter().rev().copied(), wrapper.suffix.lines().rev())?;
+    if start > end {
+        return None;
+    }
+
+    let body = lines[start..end]
+        .iter()
+        .map(|l| format!("{l}\n"))
+        .collect::<String>();
+    Some(dedent(&body))
+}
+
+/// Matches the lines of the wrapper against the start of the output. Formatters
+/// can change whitespace and add blank lines, so those are ignored. Returns the
+/// number of output lines that belong to the wrapper.
+fn match_lines<'a>(
+    mut output: impl Iterator<Item = &'a str>,
+    wrapper: impl Iterator<Item = &'a str>,
+) -> Option<usize> {
+    let mut count = 0;
+    for expected in wrapper.filter(|l| !l.trim().is_empty()) {
+        let line = loop {
+            let line = output.next()?;
+            count += 1;
+            if !line.trim().is_empty() {
+                break line;
+            }
+        };
+        if line.split_whitespace().collect::<String>()
+            != expected.split_whitespace().collect::<String>()
+        {
+            return None;
+        }
+    }
+    Some(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_roundtrip() {
+        let wrapper = Wrapper {
+            prefix: "package main\nfunc main() {".to_string(),
+            suffix: "}".to_string(),
+        };
+        let content = "x := 1\n";
+        assert_eq!(
+            "package main\nfunc main() {\nx := 1\n}\n",
+            wrap(&wrapper, content)
+        );
+        let output = "package main\n\nfunc main() {\n\tx := 1\n\n\ty := 2\n}\n";
+        assert_eq!(
+            Some("x := 1\n\ny := 2\n".to_string()),
+            unwrap(&wrapper, output)
+        );
+    }
+
+    #[test]
+    fn test_unwrap_mismatch() {
+        let wrapper = Wrapper {
+            prefix: "fn main() {\n".to_string(),
+            suffix: "}\n".to_string(),
+        };
+        assert_eq!(None, unwrap(&wrapper, "fn main() { let x = 1; }\n"));
+        assert_eq!(
+            Some("let x = 1;\n".to_string()),
+            unwrap(&wrapper, "fn main(){\n    let x = 1;\n}\n")
+        );
+    }
+}